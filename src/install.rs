@@ -3,8 +3,94 @@ fn main() {
     panic!("This program is not intended to run on this platform.");
 }
 
+#[cfg(any(windows, target_os = "linux", target_os = "macos"))]
+mod install_info;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod utils;
+
 #[cfg(not(windows))]
 use anyhow::Error;
+#[cfg(any(windows, target_os = "linux", target_os = "macos"))]
+use install_info::{state_dir, InstallInfo, INSTALL_INFO_FILE};
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use utils::OutputTimeout;
+
+#[cfg(any(windows, target_os = "linux", target_os = "macos"))]
+const LISTEN_PORT: u16 = 33211;
+
+/// Record how the service was installed, in the state directory, for /get_install_info.
+#[cfg(any(windows, target_os = "linux", target_os = "macos"))]
+fn write_install_info(
+    binary_path: &std::path::Path,
+    service_file: &str,
+    method: &str,
+    account: &str,
+) {
+    let info = InstallInfo {
+        binary_path: binary_path.to_string_lossy().into_owned(),
+        service_file: service_file.into(),
+        method: method.into(),
+        account: account.into(),
+        auto_start: true,
+        version: env!("CARGO_PKG_VERSION").into(),
+    };
+    let state_dir = state_dir();
+    let written = serde_json::to_string(&info)
+        .map_err(std::io::Error::from)
+        .and_then(|info| {
+            std::fs::create_dir_all(&state_dir)?;
+            std::fs::write(state_dir.join(INSTALL_INFO_FILE), info)
+        });
+    if let Err(err) = written {
+        eprintln!("Failed to write install info: {err}");
+    }
+}
+
+/// Send `GET /version` to the service and check that it answers as the Clash Verge Service.
+#[cfg(any(windows, target_os = "linux", target_os = "macos"))]
+fn ping_service() -> std::io::Result<bool> {
+    use std::io::{Read, Write};
+    use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+    use std::time::Duration;
+
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, LISTEN_PORT));
+    let mut stream = TcpStream::connect_timeout(&addr, Duration::from_secs(1))?;
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    stream.write_all(b"GET /version HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\n\r\n")?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response.starts_with("HTTP/1.1 200") && response.contains("Clash Verge Service"))
+}
+
+/// Wait until the freshly started service is reachable, exiting with an error if it never comes up.
+#[cfg(any(windows, target_os = "linux", target_os = "macos"))]
+fn verify_service() {
+    use std::time::{Duration, Instant};
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let mut delay = Duration::from_millis(200);
+    loop {
+        if let Ok(true) = ping_service() {
+            return;
+        }
+        if Instant::now() >= deadline {
+            eprintln!("The service was started but did not become reachable.");
+            std::process::exit(3);
+        }
+        std::thread::sleep(delay);
+        delay = (delay * 2).min(Duration::from_secs(2));
+    }
+}
+/// Compare two files byte by byte.
+#[cfg(target_os = "macos")]
+fn same_content(a: &std::path::Path, b: &std::path::Path) -> bool {
+    match (std::fs::read(a), std::fs::read(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
 #[cfg(target_os = "macos")]
 fn main() -> Result<(), Error> {
     use std::fs::File;
@@ -25,53 +111,149 @@ fn main() -> Result<(), Error> {
             .expect("Unable to create directory for service file");
     }
 
-    std::fs::copy(service_binary_path, target_binary_path).expect("Unable to copy service file");
-
     let plist_file = "/Library/LaunchDaemons/io.github.clashverge.helper.plist";
     let plist_file = Path::new(plist_file);
 
+    // Stop the running daemon so the old binary is no longer in use.
+    let upgrading = plist_file.exists();
+    let old_plist = std::fs::read(plist_file).ok();
+    if upgrading {
+        if let Err(err) = std::process::Command::new("launchctl")
+            .arg("unload")
+            .arg(plist_file)
+            .output_timeout()
+        {
+            eprintln!("Failed to unload service: {err}");
+            std::process::exit(4);
+        }
+    }
+    let reload_old_service = || {
+        if upgrading {
+            if let Err(err) = std::process::Command::new("launchctl")
+                .arg("load")
+                .arg(plist_file)
+                .output_timeout()
+            {
+                eprintln!("Failed to reload the old service: {err}");
+            }
+        }
+    };
+
+    // Stage the new binary next to the target and verify it before swapping it in.
+    let staged_binary_path = format!("{target_binary_path}.new");
+    let backup_binary_path = format!("{target_binary_path}.bak");
+    let staged = std::fs::copy(&service_binary_path, &staged_binary_path)
+        .map_err(|err| err.to_string())
+        .and_then(|_| {
+            if same_content(&service_binary_path, Path::new(&staged_binary_path)) {
+                Ok(())
+            } else {
+                Err("the copied service file does not match the original".into())
+            }
+        });
+    if let Err(err) = staged {
+        let _ = std::fs::remove_file(&staged_binary_path);
+        reload_old_service();
+        eprintln!("Unable to copy the service file: {err}");
+        std::process::exit(4);
+    }
+
+    // Keep the old binary reachable under .bak without moving it, then rename the new one
+    // over the target. The rename replaces it atomically, there is never a moment without
+    // a binary at the target path.
+    let has_old_binary = Path::new(target_binary_path).exists();
+    if has_old_binary {
+        let _ = std::fs::remove_file(&backup_binary_path);
+        if let Err(err) = std::fs::hard_link(target_binary_path, &backup_binary_path) {
+            let _ = std::fs::remove_file(&staged_binary_path);
+            reload_old_service();
+            eprintln!("Unable to back up the old service file: {err}");
+            std::process::exit(4);
+        }
+    }
+    if let Err(err) = std::fs::rename(&staged_binary_path, target_binary_path) {
+        let _ = std::fs::remove_file(&staged_binary_path);
+        let _ = std::fs::remove_file(&backup_binary_path);
+        reload_old_service();
+        eprintln!("Unable to replace the service file: {err}");
+        std::process::exit(4);
+    }
+
+    // From here on a failure puts the old binary and plist back and reloads the old daemon.
+    let fail = |what: &str, err: std::io::Error| -> ! {
+        let _ = std::process::Command::new("launchctl")
+            .arg("unload")
+            .arg(plist_file)
+            .output_timeout();
+        if has_old_binary {
+            let _ = std::fs::rename(&backup_binary_path, target_binary_path);
+        }
+        if let Some(old_plist) = &old_plist {
+            let _ = std::fs::write(plist_file, old_plist);
+        }
+        reload_old_service();
+        eprintln!("{what}: {err}");
+        std::process::exit(4);
+    };
+
     let plist_file_content = include_str!("io.github.clashverge.helper.plist");
-    let mut file = File::create(plist_file).expect("Failed to create file for writing.");
-    file.write_all(plist_file_content.as_bytes())
-        .expect("Unable to write plist file");
-    std::process::Command::new("chmod")
+    if let Err(err) =
+        File::create(plist_file).and_then(|mut file| file.write_all(plist_file_content.as_bytes()))
+    {
+        fail("Unable to write plist file", err);
+    }
+    if let Err(err) = std::process::Command::new("chmod")
         .arg("644")
         .arg(plist_file)
-        .output()
-        .expect("Failed to chmod");
-    std::process::Command::new("chown")
+        .run_checked()
+    {
+        fail("Failed to chmod", err);
+    }
+    if let Err(err) = std::process::Command::new("chown")
         .arg("root:wheel")
         .arg(plist_file)
-        .output()
-        .expect("Failed to chown");
-    std::process::Command::new("chmod")
+        .run_checked()
+    {
+        fail("Failed to chown", err);
+    }
+    if let Err(err) = std::process::Command::new("chmod")
         .arg("544")
         .arg(target_binary_path)
-        .output()
-        .expect("Failed to chmod");
-    std::process::Command::new("chown")
+        .run_checked()
+    {
+        fail("Failed to chmod", err);
+    }
+    if let Err(err) = std::process::Command::new("chown")
         .arg("root:wheel")
         .arg(target_binary_path)
-        .output()
-        .expect("Failed to chown");
-    // Unload before load the service.
-    std::process::Command::new("launchctl")
-        .arg("unload")
-        .arg(plist_file)
-        .output()
-        .expect("Failed to unload service.");
+        .run_checked()
+    {
+        fail("Failed to chown", err);
+    }
     // Load the service.
-    std::process::Command::new("launchctl")
+    if let Err(err) = std::process::Command::new("launchctl")
         .arg("load")
         .arg(plist_file)
-        .output()
-        .expect("Failed to load service.");
+        .run_checked()
+    {
+        fail("Failed to load service", err);
+    }
     // Start the service.
-    std::process::Command::new("launchctl")
+    if let Err(err) = std::process::Command::new("launchctl")
         .arg("start")
         .arg("io.github.clashverge.helper")
-        .output()
-        .expect("Failed to load service.");
+        .run_checked()
+    {
+        fail("Failed to start service", err);
+    }
+    let _ = std::fs::remove_file(&backup_binary_path);
+    write_install_info(
+        Path::new(target_binary_path),
+        &plist_file.to_string_lossy(),
+        "launchd",
+        "root",
+    );
+    verify_service();
     Ok(())
 }
 #[cfg(target_os = "linux")]
@@ -94,7 +276,7 @@ fn main() -> Result<(), Error> {
         .arg("status")
         .arg(format!("{}.service", SERVICE_NAME))
         .arg("--no-pager")
-        .output()
+        .output_timeout()
         .expect("Failed to execute 'systemctl status' command.")
         .status
         .code();
@@ -104,13 +286,17 @@ fn main() -> Result<(), Error> {
      */
     match status_code {
         Some(code) => match code {
-            0 => return Ok(()),
-            1 | 2 | 3 => {
+            0 => {
+                verify_service();
+                return Ok(());
+            }
+            1..=3 => {
                 std::process::Command::new("systemctl")
                     .arg("start")
                     .arg(format!("{}.service", SERVICE_NAME))
-                    .output()
+                    .run_checked()
                     .expect("Failed to execute 'systemctl start' command.");
+                verify_service();
                 return Ok(());
             }
             4 => {}
@@ -137,15 +323,22 @@ fn main() -> Result<(), Error> {
     // Reload unit files and start service.
     std::process::Command::new("systemctl")
         .arg("daemon-reload")
-        .output()
+        .run_checked()
         .and_then(|_| {
             std::process::Command::new("systemctl")
                 .arg("enable")
                 .arg(SERVICE_NAME)
                 .arg("--now")
-                .output()
+                .run_checked()
         })
         .expect("Failed to start service.");
+    write_install_info(
+        &service_binary_path,
+        &unit_file.to_string_lossy(),
+        "systemd",
+        "root",
+    );
+    verify_service();
     Ok(())
 }
 
@@ -177,6 +370,7 @@ fn main() -> windows_service::Result<()> {
                 _ => {}
             };
 
+            verify_service();
             return Ok(());
         }
     }
@@ -196,7 +390,7 @@ fn main() -> windows_service::Result<()> {
         service_type: ServiceType::OWN_PROCESS,
         start_type: ServiceStartType::AutoStart,
         error_control: ServiceErrorControl::Normal,
-        executable_path: service_binary_path,
+        executable_path: service_binary_path.clone(),
         launch_arguments: vec![],
         dependencies: vec![],
         account_name: None, // run as System
@@ -208,6 +402,13 @@ fn main() -> windows_service::Result<()> {
 
     service.set_description("Clash Verge Service helps to launch clash core")?;
     service.start(&Vec::<&OsStr>::new())?;
+    write_install_info(
+        &service_binary_path,
+        "clash_verge_service",
+        "scm",
+        "LocalSystem",
+    );
+    verify_service();
 
     Ok(())
 }
@@ -0,0 +1,46 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// name of the install info file, in `state_dir()`
+pub const INSTALL_INFO_FILE: &str = "clash-verge-service.install.json";
+
+/// start settings of the core to bring back after /restart_service, in `state_dir()`
+#[allow(dead_code)] // written by the service, removed by uninstall-service
+pub const RESUME_FILE: &str = "clash-verge-service.resume.json";
+
+/// where install-service and the service keep their files, the binary's own
+/// directory may be /usr/bin, /Library/PrivilegedHelperTools or Program Files
+#[cfg(not(any(windows, target_os = "macos")))]
+pub fn state_dir() -> PathBuf {
+    PathBuf::from("/var/lib/clash-verge-service")
+}
+
+#[cfg(target_os = "macos")]
+pub fn state_dir() -> PathBuf {
+    PathBuf::from("/Library/Application Support/io.github.clashverge.helper")
+}
+
+#[cfg(windows)]
+pub fn state_dir() -> PathBuf {
+    let program_data = std::env::var_os("ProgramData").unwrap_or_else(|| r"C:\ProgramData".into());
+    PathBuf::from(program_data).join("clash-verge-service")
+}
+
+/// written by install-service, served by the service at /get_install_info
+#[derive(Debug, Deserialize, Serialize)]
+pub struct InstallInfo {
+    pub binary_path: String,
+
+    /// systemd unit, launchd plist or SCM service name
+    pub service_file: String,
+
+    /// `systemd`, `launchd` or `scm`
+    pub method: String,
+
+    pub account: String,
+
+    pub auto_start: bool,
+
+    /// version of the installer that wrote this file
+    pub version: String,
+}
@@ -1,11 +1,13 @@
+mod install_info;
 mod service;
+mod utils;
 
-#[cfg(windows)]
+#[cfg(all(windows, feature = "scm"))]
 fn main() -> windows_service::Result<()> {
     service::main()
 }
 
-#[cfg(not(windows))]
+#[cfg(not(all(windows, feature = "scm")))]
 fn main() {
     service::main();
 }
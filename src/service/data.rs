@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct StartBody {
@@ -11,11 +12,416 @@ pub struct StartBody {
     pub config_file: String,
 
     pub log_file: String,
+
+    /// spawn the core with a minimal PATH and only an allowlist of the
+    /// service's variables, recommended since the service runs as root / SYSTEM
+    pub clean_env: Option<bool>,
+
+    /// niceness for the core, -20 (highest) to 19 (lowest), mapped to a
+    /// priority class on Windows
+    pub priority: Option<i32>,
+
+    /// refuse to start the core unless its binary has this SHA-256 (hex)
+    pub expected_sha256: Option<String>,
+
+    /// probe the external controller this often and restart a hung core,
+    /// at least 1000
+    pub watchdog_interval_ms: Option<u64>,
+
+    /// consecutive failed probes before the core is restarted, defaults to 3
+    pub watchdog_max_failures: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct StartFileBody {
+    /// absolute path to a JSON file holding a `StartBody`
+    pub path: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ClashInfo {
+    #[serde(flatten)]
+    pub info: StartBody,
+
+    /// seconds since the core was last started
+    pub running_secs: u64,
+
+    /// client currently holding the core lock
+    pub owner: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CoreLockBody {
+    pub owner_id: String,
+
+    /// take or release the lock even if another client holds it
+    pub force: Option<bool>,
+}
+
+/// external controller settings read from the running core's config
+#[derive(Default, Deserialize, Serialize)]
+pub struct ControllerInfo {
+    #[serde(rename(deserialize = "external-controller"))]
+    pub external_controller: Option<String>,
+
+    /// unix socket, relative paths are under config_dir
+    #[serde(rename(deserialize = "external-controller-unix"))]
+    pub external_controller_unix: Option<String>,
+
+    #[serde(rename(deserialize = "external-controller-pipe"))]
+    pub external_controller_pipe: Option<String>,
+
+    pub secret: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Capabilities {
+    /// bumped whenever an existing endpoint changes incompatibly
+    pub protocol_version: u32,
+
+    pub version: String,
+
+    /// endpoints handled by this build, e.g. `GET /version`
+    pub commands: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LogPaths {
+    /// absolute path of the core's log file, `None` if the core isn't running
+    pub core_log: Option<String>,
+}
+
+/// everything support needs to triage an issue, fields that
+/// couldn't be collected are `None` and explained in `errors`
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Diagnostics {
+    pub version: String,
+
+    pub uptime_secs: u64,
+
+    pub os: String,
+
+    pub arch: String,
+
+    pub listen_addr: String,
+
+    pub core_running: bool,
+
+    pub core_pid: Option<u32>,
+
+    pub core_exit_code: Option<i32>,
+
+    pub core_log: Option<String>,
+
+    pub core_log_size: Option<u64>,
+
+    pub bin_exists: Option<bool>,
+
+    pub bin_executable: Option<bool>,
+
+    pub errors: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SwitchConfigBody {
+    pub config_file: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct WaitBody {
+    /// capped at 60000
+    pub timeout_ms: u64,
+}
+
+/// how the core exited, see /wait_core_exit
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CoreExit {
+    /// `None` if it was killed by a signal, or stopped or restarted by the service
+    pub exit_code: Option<i32>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LimitQuery {
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RestartQuery {
+    /// start the core again with the same settings once the service is back,
+    /// otherwise it stays stopped
+    pub restart_core: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BinaryHashBody {
+    pub bin_path: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BinaryHash {
+    pub bin_path: String,
+
+    pub size: u64,
+
+    /// lowercase hex
+    pub sha256: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChunkQuery {
+    pub offset: Option<u64>,
+
+    /// capped by the service, read on from `offset + len` until `eof`
+    pub limit: Option<usize>,
+}
+
+/// a slice of the running core's config file
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ConfigChunk {
+    pub path: String,
+
+    /// size of the whole file
+    pub size: u64,
+
+    pub offset: u64,
+
+    /// base64 encoded bytes
+    pub data: String,
+
+    pub eof: bool,
+}
+
+/// an error the service returned to a client
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ErrorRecord {
+    /// endpoint that failed, e.g. `start_clash`
+    pub command: String,
+
+    pub code: u64,
+
+    pub msg: String,
+
+    /// unix timestamp in seconds
+    pub time: u64,
+}
+
+/// paths to check before a start, the running core's are used for those left out
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DiskUsageQuery {
+    pub config_dir: Option<String>,
+
+    /// relative to `config_dir` like `StartBody::log_file`, defaults to `config_dir`
+    /// when only `config_dir` is given
+    pub log_file: Option<String>,
+}
+
+/// free space on the filesystem holding a path, see /get_disk_usage
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct DiskSpace {
+    pub path: String,
+
+    pub mount_point: Option<String>,
+
+    pub total_bytes: u64,
+
+    pub available_bytes: u64,
+
+    pub used_bytes: u64,
+
+    /// set when the path or its filesystem couldn't be found
+    pub error: Option<String>,
+}
+
+/// a process that looks like a core, see /list_core_processes
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CoreProcess {
+    pub pid: u32,
+
+    pub name: String,
+
+    pub cmd: Vec<String>,
+
+    pub exe: Option<String>,
+
+    /// whether it's the core this service started
+    pub managed: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct KillProcessBody {
+    pub pid: u32,
+}
+
+/// the service process's own usage, see /get_service_resource_usage
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ResourceUsage {
+    pub pid: u32,
+
+    /// resident memory in bytes
+    pub rss_bytes: u64,
+
+    /// percent of one cpu, may exceed 100 on multi-core machines
+    pub cpu_percent: f32,
+
+    /// only available on Linux
+    pub threads: Option<usize>,
+}
+
+/// whether the service can set up TUN, see /check_privileges
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Privileges {
+    /// root on macOS / Linux, elevated on Windows
+    pub elevated: bool,
+
+    /// only known on Linux
+    pub net_admin: Option<bool>,
+
+    pub tun_capable: bool,
+}
+
+/// how often an endpoint was called since the service started
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct CommandMetrics {
+    pub count: u64,
+
+    pub error_count: u64,
+
+    /// unix timestamp in seconds
+    pub last_invoked: u64,
 }
 
+/// Stable codes for `JsonResponse::code`, `0` means success.
+/// Clients should match on these rather than on `msg`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// the request is invalid, e.g. a bad path or parameter
+    BadCommand = 4001,
+
+    /// the command needs a running core
+    CoreNotRunning = 4002,
+
+    /// the core binary doesn't exist
+    BinaryNotFound = 4003,
+
+    /// the service isn't allowed to access something
+    PermissionDenied = 4004,
+
+    /// another client holds the core lock
+    CoreLocked = 4005,
+
+    /// the core didn't reach the expected state in time
+    Timeout = 4006,
+
+    /// the core binary doesn't match `StartBody::expected_sha256`
+    HashMismatch = 4007,
+
+    /// anything else
+    Internal = 5000,
+}
+
+impl ErrorCode {
+    /// Pick the code for an error returned by a handler.
+    pub fn of(err: &anyhow::Error) -> ErrorCode {
+        for cause in err.chain() {
+            if let Some(err) = cause.downcast_ref::<ServiceError>() {
+                return err.code;
+            }
+            if let Some(err) = cause.downcast_ref::<std::io::Error>() {
+                if err.kind() == std::io::ErrorKind::PermissionDenied {
+                    return ErrorCode::PermissionDenied;
+                }
+            }
+        }
+        ErrorCode::Internal
+    }
+}
+
+/// An error that tells the client which `ErrorCode` it is.
+#[derive(Debug)]
+pub struct ServiceError {
+    pub code: ErrorCode,
+    pub msg: String,
+}
+
+impl ServiceError {
+    pub fn new(code: ErrorCode, msg: impl Into<String>) -> Self {
+        Self {
+            code,
+            msg: msg.into(),
+        }
+    }
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.msg)
+    }
+}
+
+impl std::error::Error for ServiceError {}
+
 #[derive(Deserialize, Serialize)]
 pub struct JsonResponse<T: Serialize> {
     pub code: u64,
     pub msg: String,
     pub data: Option<T>,
 }
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct StopResult {
+    /// whether the core spawned by the service was killed, `None` if there was none
+    pub child_killed: Option<bool>,
+
+    /// why the core spawned by the service couldn't be killed
+    pub child_error: Option<String>,
+
+    /// false if no processes could be listed, so strays may have been missed
+    pub sweep_available: bool,
+
+    /// time spent listing and sweeping stray processes
+    pub sweep_ms: u64,
+
+    /// pids of stray core processes killed by name
+    pub swept: Vec<u32>,
+
+    /// pids of stray core processes that could not be killed
+    pub failed: Vec<u32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn error_code_of_service_error() {
+        let err = anyhow::Error::new(ServiceError::new(ErrorCode::CoreLocked, "locked"));
+
+        assert_eq!(ErrorCode::of(&err), ErrorCode::CoreLocked);
+    }
+
+    #[test]
+    fn error_code_of_wrapped_service_error() {
+        let err = Err::<(), _>(ServiceError::new(ErrorCode::Timeout, "too slow"))
+            .context("waiting for the core")
+            .unwrap_err();
+
+        assert_eq!(ErrorCode::of(&err), ErrorCode::Timeout);
+    }
+
+    #[test]
+    fn error_code_of_permission_denied() {
+        let io = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        let err = anyhow::Error::new(io).context("failed to open log");
+
+        assert_eq!(ErrorCode::of(&err), ErrorCode::PermissionDenied);
+    }
+
+    #[test]
+    fn error_code_of_anything_else() {
+        let io = std::io::Error::from(std::io::ErrorKind::NotFound);
+
+        assert_eq!(ErrorCode::of(&anyhow::Error::new(io)), ErrorCode::Internal);
+        assert_eq!(ErrorCode::of(&anyhow::anyhow!("oops")), ErrorCode::Internal);
+    }
+}
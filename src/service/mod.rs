@@ -1,14 +1,18 @@
+mod connections;
 mod data;
+mod privileges;
 mod web;
 
 use self::data::*;
 use self::web::*;
+use serde::de::DeserializeOwned;
+use std::{process::Command, time::Duration};
 use tokio::runtime::Runtime;
-use warp::Filter;
+use warp::{Filter, Rejection};
 
-#[cfg(windows)]
-use std::{ffi::OsString, time::Duration};
-#[cfg(windows)]
+#[cfg(all(windows, feature = "scm"))]
+use std::ffi::OsString;
+#[cfg(all(windows, feature = "scm"))]
 use windows_service::{
     define_windows_service,
     service::{
@@ -19,90 +23,450 @@ use windows_service::{
     service_dispatcher, Result,
 };
 
-#[cfg(windows)]
+#[cfg(all(windows, feature = "scm"))]
 const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+#[cfg(all(windows, feature = "scm"))]
 const SERVICE_NAME: &str = "clash_verge_service";
+#[cfg(target_os = "macos")]
+const LAUNCHD_TARGET: &str = "system/io.github.clashverge.helper";
 const LISTEN_PORT: u16 = 33211;
+/// exit code when the service can't listen, the systemd unit's RestartPreventExitStatus
+/// keeps it from restarting every RestartSec against a port that stays taken
+#[cfg(not(target_os = "macos"))]
+const EXIT_CANNOT_LISTEN: i32 = 78;
+/// launchd's KeepAlive only tells a successful exit from a failed one, and
+/// SuccessfulExit=false in the plist means a clean exit is not restarted
+#[cfg(target_os = "macos")]
+const EXIT_CANNOT_LISTEN: i32 = 0;
+/// give the response time to reach the client before the service goes away
+const RESPONSE_GRACE: Duration = Duration::from_millis(500);
 
 macro_rules! wrap_response {
-    ($expr: expr) => {
+    ($name: expr, $expr: expr) => {
         match $expr {
-            Ok(data) => warp::reply::json(&JsonResponse {
-                code: 0,
-                msg: "ok".into(),
-                data: Some(data),
-            }),
-            Err(err) => warp::reply::json(&JsonResponse {
-                code: 400,
-                msg: format!("{err}"),
-                data: Option::<()>::None,
-            }),
+            Ok(data) => {
+                record_command($name, true);
+                warp::reply::json(&JsonResponse {
+                    code: 0,
+                    msg: "ok".into(),
+                    data: Some(data),
+                })
+            }
+            Err(err) => {
+                record_command($name, false);
+                let code = ErrorCode::of(&err);
+                let msg = format!("{err}");
+                if is_service_fault($name, code) {
+                    record_service_error($name, code as u64, &msg);
+                }
+                warp::reply::json(&JsonResponse {
+                    code: code as u64,
+                    msg,
+                    data: Option::<()>::None,
+                })
+            }
         }
     };
 }
 
 /// The Service
+/// 与平台无关的服务主循环，不依赖 Windows SCM
 pub async fn run_service() -> anyhow::Result<()> {
-    // 开启服务 设置服务状态
-    #[cfg(windows)]
-    let status_handle = service_control_handler::register(
-        SERVICE_NAME,
-        move |event| -> ServiceControlHandlerResult {
-            match event {
-                ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
-                ServiceControl::Stop => std::process::exit(0),
-                _ => ServiceControlHandlerResult::NotImplemented,
-            }
-        },
-    )?;
-    #[cfg(windows)]
-    status_handle.set_service_status(ServiceStatus {
-        service_type: SERVICE_TYPE,
-        current_state: ServiceState::Running,
-        controls_accepted: ServiceControlAccept::STOP,
-        exit_code: ServiceExitCode::Win32(0),
-        checkpoint: 0,
-        wait_hint: Duration::default(),
-        process_id: None,
-    })?;
+    service_started_at();
 
+    let listener = match tokio::net::TcpListener::bind(("127.0.0.1", LISTEN_PORT)).await {
+        Ok(listener) => listener,
+        Err(err) if service_running().await => {
+            anyhow::bail!("another service instance is already running: {err}")
+        }
+        Err(err) => anyhow::bail!("failed to listen on 127.0.0.1:{LISTEN_PORT}: {err}"),
+    };
+    // bring back the core /restart_service was asked to keep
+    if let Err(err) = blocking(resume_core).await {
+        record_service_error(
+            "restart_service",
+            ErrorCode::of(&err) as u64,
+            &format!("failed to resume the core: {err}"),
+        );
+    }
+
+    warp::serve(routes())
+        .serve_incoming_with_graceful_shutdown(connections::incoming(listener), shutdown_signal())
+        .await;
+
+    // don't leave the core orphaned when the service manager stops us
+    let _ = blocking(|| stop_clash().map(|_| ())).await;
+
+    Ok(())
+}
+
+/// 所有接口，新增接口也要加到 `COMMANDS`
+fn routes() -> impl Filter<Extract = (impl warp::Reply,), Error = Rejection> + Clone {
     let api_get_version = warp::get()
         .and(warp::path("version"))
-        .map(move || wrap_response!(get_version()));
+        .map(move || wrap_response!("version", get_version()));
+
+    let api_get_capabilities = warp::get()
+        .and(warp::path("capabilities"))
+        .map(move || wrap_response!("capabilities", get_capabilities()));
 
     let api_start_clash = warp::post()
         .and(warp::path("start_clash"))
-        .and(warp::body::json())
-        .map(move |body: StartBody| wrap_response!(start_clash(body)));
+        .and(core_owner("start_clash"))
+        .and(json_body("start_clash"))
+        .then(|owner, force, body: StartBody| async move {
+            let result = match check_core_owner(owner, force) {
+                Ok(()) => start_clash_cancellable(body).await,
+                Err(err) => Err(err),
+            };
+            wrap_response!("start_clash", result)
+        });
+
+    let api_start_clash_from_file = warp::post()
+        .and(warp::path("start_clash_from_file"))
+        .and(core_owner("start_clash_from_file"))
+        .and(json_body("start_clash_from_file"))
+        .then(|owner, force, body: StartFileBody| async move {
+            let result = blocking(move || {
+                check_core_owner(owner, force).and_then(|_| start_clash_from_file(body))
+            });
+            wrap_response!("start_clash_from_file", result.await)
+        });
+
+    let api_switch_config = warp::post()
+        .and(warp::path("switch_config"))
+        .and(core_owner("switch_config"))
+        .and(json_body("switch_config"))
+        .then(|owner, force, body: SwitchConfigBody| async move {
+            let result =
+                blocking(move || check_core_owner(owner, force).and_then(|_| switch_config(body)));
+            wrap_response!("switch_config", result.await)
+        });
 
     let api_stop_clash = warp::post()
         .and(warp::path("stop_clash"))
-        .map(move || wrap_response!(stop_clash()));
+        .and(core_owner("stop_clash"))
+        .then(|owner, force| async move {
+            let result =
+                blocking(move || check_core_owner(owner, force).and_then(|_| stop_clash()));
+            wrap_response!("stop_clash", result.await)
+        });
+
+    let api_list_core_processes =
+        warp::get()
+            .and(warp::path("list_core_processes"))
+            .then(|| async {
+                wrap_response!("list_core_processes", blocking(list_core_processes).await)
+            });
+
+    let api_kill_core_process = warp::post()
+        .and(warp::path("kill_core_process"))
+        .and(json_body("kill_core_process"))
+        .then(|body: KillProcessBody| async move {
+            wrap_response!(
+                "kill_core_process",
+                blocking(move || kill_core_process(body)).await
+            )
+        });
+
+    let api_acquire_core_lock = warp::post()
+        .and(warp::path("acquire_core_lock"))
+        .and(json_body("acquire_core_lock"))
+        .map(move |body: CoreLockBody| {
+            wrap_response!("acquire_core_lock", acquire_core_lock(body))
+        });
+
+    let api_release_core_lock = warp::post()
+        .and(warp::path("release_core_lock"))
+        .and(json_body("release_core_lock"))
+        .map(move |body: CoreLockBody| {
+            wrap_response!("release_core_lock", release_core_lock(body))
+        });
 
     let api_get_clash = warp::get()
         .and(warp::path("get_clash"))
-        .map(move || wrap_response!(get_clash()));
+        .map(move || wrap_response!("get_clash", get_clash()));
+
+    let api_get_controller = warp::get()
+        .and(warp::path("get_controller"))
+        .then(|| async { wrap_response!("get_controller", blocking(get_controller).await) });
+
+    let api_read_config_file = warp::get()
+        .and(warp::path("read_config_file"))
+        .and(query::<ChunkQuery>("read_config_file"))
+        .then(|query: ChunkQuery| async move {
+            wrap_response!(
+                "read_config_file",
+                blocking(move || read_config_file(query)).await
+            )
+        });
+
+    let api_wait_core_ready = warp::post()
+        .and(warp::path("wait_core_ready"))
+        .and(json_body("wait_core_ready"))
+        .then(|body: WaitBody| async move {
+            wrap_response!("wait_core_ready", wait_core_ready(body).await)
+        });
+
+    let api_wait_core_exit = warp::post()
+        .and(warp::path("wait_core_exit"))
+        .and(json_body("wait_core_exit"))
+        .then(|body: WaitBody| async move {
+            wrap_response!("wait_core_exit", wait_core_exit(body).await)
+        });
+
+    let api_get_log_path = warp::get()
+        .and(warp::path("get_log_path"))
+        .map(move || wrap_response!("get_log_path", get_log_path()));
+
+    let api_get_diagnostics = warp::get()
+        .and(warp::path("diagnostics"))
+        .then(|| async { wrap_response!("diagnostics", blocking(get_diagnostics).await) });
+
+    let api_get_install_info = warp::get()
+        .and(warp::path("get_install_info"))
+        .then(|| async { wrap_response!("get_install_info", blocking(get_install_info).await) });
+
+    let api_get_disk_usage = warp::get()
+        .and(warp::path("get_disk_usage"))
+        .and(query::<DiskUsageQuery>("get_disk_usage"))
+        .then(|query: DiskUsageQuery| async move {
+            wrap_response!(
+                "get_disk_usage",
+                blocking(move || get_disk_usage(query)).await
+            )
+        });
+
+    let api_get_service_resource_usage = warp::get()
+        .and(warp::path("get_service_resource_usage"))
+        .then(|| async {
+            wrap_response!(
+                "get_service_resource_usage",
+                blocking(get_service_resource_usage).await
+            )
+        });
+
+    let api_check_privileges = warp::get()
+        .and(warp::path("check_privileges"))
+        .map(move || wrap_response!("check_privileges", check_privileges()));
 
+    let api_get_service_journal = warp::get()
+        .and(warp::path("get_service_journal"))
+        .and(query::<LimitQuery>("get_service_journal"))
+        .then(|query: LimitQuery| async move {
+            wrap_response!(
+                "get_service_journal",
+                blocking(move || get_service_journal(query)).await
+            )
+        });
+
+    let api_get_connection_count = warp::get()
+        .and(warp::path("get_connection_count"))
+        .map(move || wrap_response!("get_connection_count", get_connection_count()));
+
+    let api_get_core_binary_hash = warp::post()
+        .and(warp::path("get_core_binary_hash"))
+        .and(json_body("get_core_binary_hash"))
+        .then(|body: BinaryHashBody| async move {
+            wrap_response!(
+                "get_core_binary_hash",
+                blocking(move || get_core_binary_hash(body)).await
+            )
+        });
+
+    let api_get_service_errors = warp::get()
+        .and(warp::path("get_service_errors"))
+        .and(query::<LimitQuery>("get_service_errors"))
+        .map(move |query: LimitQuery| {
+            wrap_response!("get_service_errors", get_service_errors(query))
+        });
+
+    let api_get_metrics = warp::get()
+        .and(warp::path("get_metrics"))
+        .map(move || wrap_response!("get_metrics", get_metrics()));
+
+    // the graceful shutdown stops the core too, so the core lock applies
     let api_stop_service = warp::post()
         .and(warp::path("stop_service"))
-        .map(|| wrap_response!(stop_service()));
-
-    warp::serve(
-        api_get_version
-            .or(api_start_clash)
-            .or(api_stop_clash)
-            .or(api_stop_service)
-            .or(api_get_clash),
-    )
-    .run(([127, 0, 0, 1], LISTEN_PORT))
-    .await;
+        .and(core_owner("stop_service"))
+        .then(|owner, force| async move {
+            let result = blocking(move || {
+                check_core_owner(owner, force)?;
+                clear_service_errors();
+                clear_metrics();
+                stop_service()
+            });
+            wrap_response!("stop_service", result.await)
+        });
 
-    Ok(())
+    let api_restart_service = warp::post()
+        .and(warp::path("restart_service"))
+        .and(core_owner("restart_service"))
+        .and(query::<RestartQuery>("restart_service"))
+        .then(|owner, force, query: RestartQuery| async move {
+            let result = blocking(move || {
+                check_core_owner(owner, force).and_then(|_| restart_service(query))
+            });
+            wrap_response!("restart_service", result.await)
+        });
+
+    api_get_version
+        .or(api_get_capabilities)
+        .or(api_start_clash)
+        .or(api_start_clash_from_file)
+        .or(api_switch_config)
+        .or(api_stop_clash)
+        .or(api_list_core_processes)
+        .or(api_kill_core_process)
+        .or(api_acquire_core_lock)
+        .or(api_release_core_lock)
+        .or(api_stop_service)
+        .or(api_restart_service)
+        .or(api_get_clash)
+        .or(api_get_controller)
+        .or(api_read_config_file)
+        .or(api_wait_core_ready)
+        .or(api_wait_core_exit)
+        .or(api_get_log_path)
+        .or(api_get_diagnostics)
+        .or(api_get_install_info)
+        .or(api_get_disk_usage)
+        .or(api_get_service_resource_usage)
+        .or(api_check_privileges)
+        .or(api_get_service_journal)
+        .or(api_get_connection_count)
+        .or(api_get_core_binary_hash)
+        .or(api_get_service_errors)
+        .or(api_get_metrics)
+        .recover(handle_rejection)
 }
 
-// 停止服务
-#[cfg(windows)]
-fn stop_service() -> Result<()> {
+/// 请求头、请求体或查询参数无法解析
+#[derive(Debug)]
+struct BadRequest {
+    command: &'static str,
+    msg: String,
+}
+
+impl warp::reject::Reject for BadRequest {}
+
+/// 把解析失败的拒绝换成 `BadRequest`，记下是哪个接口，其余的原样返回
+fn bad_request(command: &'static str, rejection: Rejection) -> Rejection {
+    use warp::filters::body::BodyDeserializeError;
+    use warp::reject::{InvalidHeader, InvalidQuery, UnsupportedMediaType};
+
+    let msg = if let Some(err) = rejection.find::<BodyDeserializeError>() {
+        err.to_string()
+    } else if let Some(err) = rejection.find::<UnsupportedMediaType>() {
+        err.to_string()
+    } else if let Some(err) = rejection.find::<InvalidQuery>() {
+        err.to_string()
+    } else if let Some(err) = rejection.find::<InvalidHeader>() {
+        err.to_string()
+    } else {
+        return rejection;
+    };
+    warp::reject::custom(BadRequest { command, msg })
+}
+
+/// JSON 请求体
+fn json_body<T>(command: &'static str) -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+where
+    T: DeserializeOwned + Send,
+{
+    warp::body::json()
+        .or_else(move |rejection| async move { Err::<(T,), _>(bad_request(command, rejection)) })
+}
+
+/// 查询参数
+fn query<T>(command: &'static str) -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    warp::query::<T>()
+        .or_else(move |rejection| async move { Err::<(T,), _>(bad_request(command, rejection)) })
+}
+
+/// advisory core lock, see /acquire_core_lock
+fn core_owner(
+    command: &'static str,
+) -> impl Filter<Extract = (Option<String>, Option<bool>), Error = Rejection> + Clone {
+    warp::header::optional::<String>("x-core-owner")
+        .and(warp::header::optional::<bool>("x-core-force"))
+        .or_else(move |rejection| async move {
+            Err::<(Option<String>, Option<bool>), _>(bad_request(command, rejection))
+        })
+}
+
+/// 无法解析的请求也按 BadCommand 回复并记录，找不到接口之类的交给 warp
+async fn handle_rejection(rejection: Rejection) -> Result<warp::reply::Json, Rejection> {
+    match rejection.find::<BadRequest>() {
+        Some(bad) => {
+            let err = ServiceError::new(ErrorCode::BadCommand, bad.msg.clone());
+            Ok(wrap_response!(
+                bad.command,
+                Result::<(), _>::Err(anyhow::Error::new(err))
+            ))
+        }
+        None => Err(rejection),
+    }
+}
+
+/// 收到 ctrl-c，或者 Unix 上收到 SIGTERM（systemctl stop、launchctl unload）
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// 在阻塞线程上运行会做文件、进程操作的处理函数，不占用异步工作线程
+async fn blocking<T, F>(handler: F) -> anyhow::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> anyhow::Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(handler).await?
+}
+
+/// 端口上是否已经有一个服务实例在响应
+async fn service_running() -> bool {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let probe = async {
+        let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", LISTEN_PORT)).await?;
+        stream
+            .write_all(b"GET /version HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\n\r\n")
+            .await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        std::io::Result::Ok(String::from_utf8_lossy(&response).contains("Clash Verge Service"))
+    };
+    matches!(
+        tokio::time::timeout(Duration::from_secs(2), probe).await,
+        Ok(Ok(true))
+    )
+}
+
+// 向 SCM 报告服务已停止
+#[cfg(all(windows, feature = "scm"))]
+fn report_stopped(exit_code: ServiceExitCode) -> anyhow::Result<()> {
     let status_handle =
         service_control_handler::register(SERVICE_NAME, |_| ServiceControlHandlerResult::NoError)?;
 
@@ -110,6 +474,32 @@ fn stop_service() -> Result<()> {
         service_type: SERVICE_TYPE,
         current_state: ServiceState::Stopped,
         controls_accepted: ServiceControlAccept::empty(),
+        exit_code,
+        checkpoint: 0,
+        wait_hint: Duration::default(),
+        process_id: None,
+    })?;
+
+    Ok(())
+}
+
+// 开启服务 设置服务状态
+#[cfg(all(windows, feature = "scm"))]
+fn register_service() -> Result<()> {
+    let status_handle = service_control_handler::register(
+        SERVICE_NAME,
+        move |event| -> ServiceControlHandlerResult {
+            match event {
+                ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+                ServiceControl::Stop => std::process::exit(0),
+                _ => ServiceControlHandlerResult::NotImplemented,
+            }
+        },
+    )?;
+    status_handle.set_service_status(ServiceStatus {
+        service_type: SERVICE_TYPE,
+        current_state: ServiceState::Running,
+        controls_accepted: ServiceControlAccept::STOP,
         exit_code: ServiceExitCode::Win32(0),
         checkpoint: 0,
         wait_hint: Duration::default(),
@@ -118,39 +508,308 @@ fn stop_service() -> Result<()> {
 
     Ok(())
 }
-#[cfg(not(windows))]
+
+// 停止服务，能提前发现的失败直接返回给客户端，客户端总能收到完整的响应
+#[cfg(not(any(windows, target_os = "macos")))]
+fn stop_service() -> anyhow::Result<()> {
+    // systemctl --no-block returns once the stop is queued, and the graceful
+    // shutdown on the SIGTERM that follows still sends this response
+    run_service_command(stop_command())
+}
+#[cfg(target_os = "macos")]
+fn stop_service() -> anyhow::Result<()> {
+    // bootout waits for the daemon to exit while the daemon waits for this response,
+    // so only make sure it is loaded now and boot it out once the response is sent
+    let output = Command::new("launchctl")
+        .arg("print")
+        .arg(LAUNCHD_TARGET)
+        .output()?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "the service is not loaded by launchd: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    stop_service_later(|| run_service_command(stop_command()))
+}
+#[cfg(all(windows, feature = "scm"))]
+fn stop_service() -> anyhow::Result<()> {
+    stop_service_later(|| report_stopped(ServiceExitCode::Win32(0)))
+}
+#[cfg(all(windows, not(feature = "scm")))]
 fn stop_service() -> anyhow::Result<()> {
-    // systemctl stop clash_verge_service
-    std::process::Command::new("systemctl")
+    // without the SCM there is nothing to report to, just exit
+    stop_service_later(|| std::process::exit(0))
+}
+// 响应发出后再停止，这之后的失败只能记录下来
+#[cfg(any(windows, target_os = "macos"))]
+fn stop_service_later<F>(stop: F) -> anyhow::Result<()>
+where
+    F: FnOnce() -> anyhow::Result<()> + Send + 'static,
+{
+    std::thread::spawn(move || {
+        std::thread::sleep(RESPONSE_GRACE);
+        if let Err(err) = stop() {
+            record_service_error(
+                "stop_service",
+                ErrorCode::of(&err) as u64,
+                &format!("{err}"),
+            );
+        }
+    });
+    Ok(())
+}
+#[cfg(not(windows))]
+fn run_service_command(mut command: Command) -> anyhow::Result<()> {
+    let output = command.output()?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{} failed: {}",
+            command.get_program().to_string_lossy(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+#[cfg(target_os = "macos")]
+fn stop_command() -> Command {
+    let mut command = Command::new("launchctl");
+    command.arg("bootout").arg(LAUNCHD_TARGET);
+    command
+}
+#[cfg(all(not(any(windows, target_os = "macos")), not(test)))]
+fn stop_command() -> Command {
+    let mut command = Command::new("systemctl");
+    command
+        .arg("--no-block")
         .arg("stop")
-        .arg(SERVICE_NAME)
-        .output()
-        .expect("failed to execute process");
+        .arg("clash-verge-service");
+    command
+}
+#[cfg(all(not(any(windows, target_os = "macos")), test))]
+fn stop_command() -> Command {
+    // tests must never stop the service installed on the machine running them
+    Command::new("true")
+}
+// 重启服务，先停止内核，响应发出后再重启
+// systemd 和 launchd 会连同服务一起结束内核，所以内核不能在重启期间保持运行，
+// 需要时由新的服务进程重新启动
+fn restart_service(query: RestartQuery) -> anyhow::Result<()> {
+    if query.restart_core.unwrap_or(false) {
+        save_core_for_restart()?;
+    }
+    let _ = stop_clash();
+
+    std::thread::spawn(|| {
+        std::thread::sleep(RESPONSE_GRACE);
+        let err = match restart_command().status() {
+            Ok(status) if status.success() => return,
+            Ok(status) => format!("restart command failed: {status}"),
+            Err(err) => format!("failed to run restart command: {err}"),
+        };
+        // still running, a saved core must not come back at some unrelated later start
+        discard_core_for_restart();
+        record_service_error("restart_service", ErrorCode::Internal as u64, &err);
+    });
     Ok(())
 }
-/// Service Main function
 #[cfg(windows)]
+fn restart_command() -> Command {
+    use std::os::windows::process::CommandExt;
+    const CREATE_NO_WINDOW: u32 = 0x08000000;
+
+    // Restart-Service waits for the stop before starting again, and
+    // outlives this process since the SCM doesn't kill our children
+    let mut command = Command::new("powershell");
+    command
+        .args(["-NoProfile", "-Command", "Restart-Service"])
+        .args(["-Name", "clash_verge_service", "-Force"])
+        .creation_flags(CREATE_NO_WINDOW);
+    command
+}
+#[cfg(target_os = "macos")]
+fn restart_command() -> Command {
+    let mut command = Command::new("launchctl");
+    command.arg("kickstart").arg("-k").arg(LAUNCHD_TARGET);
+    command
+}
+#[cfg(not(any(windows, target_os = "macos")))]
+fn restart_command() -> Command {
+    // --no-block: systemd finishes the job even after it kills this process
+    let mut command = Command::new("systemctl");
+    command
+        .arg("--no-block")
+        .arg("restart")
+        .arg("clash-verge-service");
+    command
+}
+/// Service Main function
+#[cfg(all(windows, feature = "scm"))]
 pub fn main() -> Result<()> {
     service_dispatcher::start(SERVICE_NAME, ffi_service_main)
 }
 
-#[cfg(not(windows))]
+#[cfg(not(all(windows, feature = "scm")))]
 pub fn main() {
     if let Ok(rt) = Runtime::new() {
-        rt.block_on(async {
-            let _ = run_service().await;
-        });
+        if let Err(err) = rt.block_on(run_service()) {
+            eprintln!("{err}");
+            std::process::exit(EXIT_CANNOT_LISTEN);
+        }
     }
 }
 
-#[cfg(windows)]
+#[cfg(all(windows, feature = "scm"))]
 define_windows_service!(ffi_service_main, my_service_main);
 
-#[cfg(windows)]
+#[cfg(all(windows, feature = "scm"))]
 pub fn my_service_main(_arguments: Vec<OsString>) {
+    if register_service().is_err() {
+        return;
+    }
     if let Ok(rt) = Runtime::new() {
-        rt.block_on(async {
-            let _ = run_service().await;
-        });
+        if rt.block_on(run_service()).is_err() {
+            // otherwise the SCM keeps showing a service that never listened as running
+            let _ = report_stopped(ServiceExitCode::ServiceSpecific(EXIT_CANNOT_LISTEN as u32));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// held by tests that touch the core lock or the metrics, other tests only read them
+    static GLOBAL_STATE: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    async fn response(request: warp::test::RequestBuilder) -> serde_json::Value {
+        let res = request.reply(&routes()).await;
+        serde_json::from_slice(res.body()).expect("response is not a JsonResponse")
+    }
+
+    /// the SIGTERM from `systemctl stop` lands while the response is still in flight
+    #[cfg(not(any(windows, target_os = "macos")))]
+    #[tokio::test]
+    async fn stop_service_round_trip() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let _state = GLOBAL_STATE.lock().await;
+        clear_metrics();
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        // the handler has run once stop_service is counted, its response isn't written yet
+        let stopping = async {
+            while !get_metrics().unwrap().contains_key("stop_service") {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        };
+        let server = tokio::spawn(
+            warp::serve(routes())
+                .serve_incoming_with_graceful_shutdown(connections::incoming(listener), stopping),
+        );
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                b"POST /stop_service HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Length: 0\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        server.await.unwrap();
+
+        let response = String::from_utf8(response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200"), "{head}");
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body["code"], 0);
+    }
+
+    #[tokio::test]
+    async fn stop_service_honours_core_lock() {
+        let _state = GLOBAL_STATE.lock().await;
+        acquire_core_lock(CoreLockBody {
+            owner_id: "gui".into(),
+            force: None,
+        })
+        .unwrap();
+
+        let res = response(warp::test::request().method("POST").path("/stop_service")).await;
+        release_core_lock(CoreLockBody {
+            owner_id: "gui".into(),
+            force: None,
+        })
+        .unwrap();
+
+        assert_eq!(res["code"], ErrorCode::CoreLocked as u64);
+    }
+
+    /// /capabilities lists `COMMANDS`, every entry must reach a route
+    #[tokio::test]
+    async fn every_command_is_routed() {
+        let _state = GLOBAL_STATE.lock().await;
+
+        for command in COMMANDS {
+            let (method, path) = command.split_once(' ').unwrap();
+            // a bad lock header or a missing body stops every POST before its handler,
+            // nothing here may stop the core or the service
+            let res = warp::test::request()
+                .method(method)
+                .path(path)
+                .header("x-core-force", "invalid")
+                .reply(&routes())
+                .await;
+
+            let status = res.status().as_u16();
+            assert!(
+                status != 404 && status != 405,
+                "{command} has no route: {status}"
+            );
+            if method == "POST" {
+                let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+                assert_eq!(
+                    body["code"],
+                    ErrorCode::BadCommand as u64,
+                    "{command} reached its handler"
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_body_is_bad_command() {
+        let res = response(
+            warp::test::request()
+                .method("POST")
+                .path("/acquire_core_lock")
+                .header("content-type", "application/json")
+                .body("not json"),
+        )
+        .await;
+
+        assert_eq!(res["code"], ErrorCode::BadCommand as u64);
+    }
+
+    #[tokio::test]
+    async fn malformed_query_is_bad_command() {
+        let res = response(warp::test::request().path("/get_service_errors?limit=lots")).await;
+
+        assert_eq!(res["code"], ErrorCode::BadCommand as u64);
+    }
+
+    #[tokio::test]
+    async fn malformed_header_is_bad_command() {
+        let res = response(
+            warp::test::request()
+                .method("POST")
+                .path("/stop_clash")
+                .header("x-core-force", "maybe"),
+        )
+        .await;
+
+        assert_eq!(res["code"], ErrorCode::BadCommand as u64);
     }
 }
@@ -0,0 +1,33 @@
+/// 服务进程是否以最高权限运行，root 或者 Windows 的管理员
+#[cfg(unix)]
+pub fn is_elevated() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(windows)]
+pub fn is_elevated() -> bool {
+    use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+
+    // only an elevated process may create services
+    ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CREATE_SERVICE).is_ok()
+}
+
+/// 当前进程是否有 CAP_NET_ADMIN，创建 TUN 设备需要它
+/// 以 root 运行但被 systemd 去掉了 capability 时也会是 false
+#[cfg(target_os = "linux")]
+pub fn has_net_admin() -> Option<bool> {
+    const CAP_NET_ADMIN: u32 = 12;
+
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let cap_eff = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))?
+        .trim();
+    let caps = u64::from_str_radix(cap_eff, 16).ok()?;
+    Some(caps & (1 << CAP_NET_ADMIN) != 0)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn has_net_admin() -> Option<bool> {
+    None
+}
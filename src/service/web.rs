@@ -1,15 +1,87 @@
+use super::connections;
 use super::data::*;
+use super::privileges;
+use crate::install_info::{state_dir, InstallInfo, INSTALL_INFO_FILE, RESUME_FILE};
+use crate::utils::OutputTimeout;
 use anyhow::{bail, Context, Result};
+use base64::prelude::*;
 use once_cell::sync::OnceCell;
 use parking_lot::Mutex;
-use std::collections::HashMap;
-use std::fs::File;
-use std::process::Command;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Child, Command};
 use std::sync::Arc;
-use sysinfo::System;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use sysinfo::{Disks, Pid, ProcessRefreshKind, RefreshKind, System, UpdateKind};
+const PROTOCOL_VERSION: u32 = 1;
+/// how many of the service's own errors to keep
+const SERVICE_ERRORS_LIMIT: usize = 20;
+/// largest chunk /read_config_file returns at once
+const CONFIG_CHUNK_LIMIT: usize = 1024 * 1024;
+/// largest start file /start_clash_from_file will read
+const START_FILE_LIMIT: u64 = 64 * 1024;
+/// largest core binary /get_core_binary_hash will read
+const CORE_BINARY_HASH_LIMIT: u64 = 256 * 1024 * 1024;
+/// processes whose name contains this are treated as cores
+const CORE_PROCESS_NAME: &str = "verge-mihomo";
+/// most lines /get_service_journal returns
+const SERVICE_JOURNAL_LIMIT: usize = 1000;
+/// longest timeout_ms a wait may ask for, so one client can't hold a connection open
+/// indefinitely and stall a graceful shutdown
+const WAIT_TIMEOUT_LIMIT_MS: u64 = 60 * 1000;
+/// longest a single /wait_core_ready probe may take
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+/// shortest watchdog_interval_ms accepted, each probe costs a connection to the core
+const WATCHDOG_MIN_INTERVAL_MS: u64 = 1000;
+
+/// every endpoint served by `run_service`, keep in sync with the routes
+pub const COMMANDS: &[&str] = &[
+    "GET /version",
+    "GET /capabilities",
+    "POST /start_clash",
+    "POST /start_clash_from_file",
+    "POST /switch_config",
+    "POST /stop_clash",
+    "GET /list_core_processes",
+    "POST /kill_core_process",
+    "POST /acquire_core_lock",
+    "POST /release_core_lock",
+    "GET /get_clash",
+    "GET /get_controller",
+    "GET /read_config_file",
+    "POST /wait_core_ready",
+    "POST /wait_core_exit",
+    "GET /get_log_path",
+    "GET /diagnostics",
+    "GET /get_install_info",
+    "GET /get_disk_usage",
+    "GET /get_service_resource_usage",
+    "GET /check_privileges",
+    "GET /get_service_journal",
+    "GET /get_connection_count",
+    "POST /get_core_binary_hash",
+    "GET /get_service_errors",
+    "GET /get_metrics",
+    "POST /stop_service",
+    "POST /restart_service",
+];
+
 #[derive(Debug, Default)]
 pub struct ClashStatus {
     pub info: Option<StartBody>,
+
+    pub child: Option<Child>,
+
+    pub last_running_time: Option<Instant>,
+
+    /// bumped on every start and stop, so stale watchdogs know to quit
+    pub generation: u64,
+
+    /// advisory lock so two clients don't fight over the core
+    pub owner: Option<String>,
 }
 
 impl ClashStatus {
@@ -20,6 +92,115 @@ impl ClashStatus {
     }
 }
 
+/// 启动和停止内核时持有，两个请求不会同时停止旧内核再各自启动一个新的
+fn core_control() -> &'static Mutex<()> {
+    static CORE_CONTROL: OnceCell<Mutex<()>> = OnceCell::new();
+
+    CORE_CONTROL.get_or_init(|| Mutex::new(()))
+}
+
+/// 服务启动的时间，第一次调用时记录
+pub fn service_started_at() -> Instant {
+    static STARTED_AT: OnceCell<Instant> = OnceCell::new();
+
+    *STARTED_AT.get_or_init(Instant::now)
+}
+
+fn service_errors() -> &'static Mutex<VecDeque<ErrorRecord>> {
+    static SERVICE_ERRORS: OnceCell<Mutex<VecDeque<ErrorRecord>>> = OnceCell::new();
+
+    SERVICE_ERRORS.get_or_init(|| Mutex::new(VecDeque::with_capacity(SERVICE_ERRORS_LIMIT)))
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+/// 记录返回给客户端的错误
+pub fn record_service_error(command: &str, code: u64, msg: &str) {
+    let time = unix_time();
+
+    let mut errors = service_errors().lock();
+    if errors.len() == SERVICE_ERRORS_LIMIT {
+        errors.pop_front();
+    }
+    errors.push_back(ErrorRecord {
+        command: command.into(),
+        code,
+        msg: msg.into(),
+        time,
+    });
+}
+
+/// 启动、停止或重启内核和服务的接口，这些接口的任何失败都要记录
+const CONTROL_COMMANDS: &[&str] = &[
+    "start_clash",
+    "start_clash_from_file",
+    "switch_config",
+    "stop_clash",
+    "stop_service",
+    "restart_service",
+];
+
+/// 接口返回的错误是否值得记录
+/// 只读查询在内核没有运行时返回 CoreNotRunning 是常态，GUI 轮询时会把真正的故障挤出去
+pub fn is_service_fault(command: &str, code: ErrorCode) -> bool {
+    match code {
+        ErrorCode::Internal
+        | ErrorCode::PermissionDenied
+        | ErrorCode::BinaryNotFound
+        | ErrorCode::HashMismatch => true,
+        _ => CONTROL_COMMANDS.contains(&command),
+    }
+}
+
+/// 清空记录的错误
+pub fn clear_service_errors() {
+    service_errors().lock().clear();
+}
+
+/// GET /get_service_errors
+/// 获取服务最近的错误，最新的在最后
+pub fn get_service_errors(query: LimitQuery) -> Result<Vec<ErrorRecord>> {
+    let errors = service_errors().lock();
+
+    let limit = query
+        .limit
+        .unwrap_or(SERVICE_ERRORS_LIMIT)
+        .min(errors.len());
+    Ok(errors.iter().skip(errors.len() - limit).cloned().collect())
+}
+
+fn command_metrics() -> &'static Mutex<HashMap<String, CommandMetrics>> {
+    static COMMAND_METRICS: OnceCell<Mutex<HashMap<String, CommandMetrics>>> = OnceCell::new();
+
+    COMMAND_METRICS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// 记录一次接口调用
+pub fn record_command(command: &str, ok: bool) {
+    let mut metrics = command_metrics().lock();
+    let entry = metrics.entry(command.into()).or_default();
+    entry.count += 1;
+    if !ok {
+        entry.error_count += 1;
+    }
+    entry.last_invoked = unix_time();
+}
+
+/// 清空接口调用计数
+pub fn clear_metrics() {
+    command_metrics().lock().clear();
+}
+
+/// GET /get_metrics
+/// 获取每个接口的调用次数和失败次数
+pub fn get_metrics() -> Result<HashMap<String, CommandMetrics>> {
+    Ok(command_metrics().lock().clone())
+}
+
 /// GET /version
 /// 获取服务进程的版本
 pub fn get_version() -> Result<HashMap<String, String>> {
@@ -33,13 +214,33 @@ pub fn get_version() -> Result<HashMap<String, String>> {
     Ok(map)
 }
 
+/// GET /capabilities
+/// 获取服务支持的接口列表和协议版本
+pub fn get_capabilities() -> Result<Capabilities> {
+    Ok(Capabilities {
+        protocol_version: PROTOCOL_VERSION,
+        version: env!("CARGO_PKG_VERSION").into(),
+        commands: COMMANDS.iter().map(|cmd| cmd.to_string()).collect(),
+    })
+}
+
 /// POST /start_clash
 /// 启动clash进程
 pub fn start_clash(body: StartBody) -> Result<()> {
+    let _control = core_control().lock();
+
+    start_core(body).map(|_| ())
+}
+
+/// 停止旧内核并启动新内核，返回新内核的 generation，调用者要持有 `core_control`
+fn start_core(body: StartBody) -> Result<u64> {
+    // a rejected request must leave the running core alone
+    check_start_body(&body)?;
+
     // stop the old clash bin
-    let _ = stop_clash();
+    stop_core();
 
-    let body_cloned = body.clone();
+    let mut body_cloned = body.clone();
 
     let config_dir = body.config_dir.as_str();
 
@@ -47,38 +248,1494 @@ pub fn start_clash(body: StartBody) -> Result<()> {
 
     let args = vec!["-d", config_dir, "-f", config_file];
 
-    let log = File::create(body.log_file).context("failed to open log")?;
-    Command::new(body.bin_path).args(args).stdout(log).spawn()?;
+    let log_file = resolve_log_file(config_dir, &body.log_file);
+    if let Some(log_dir) = log_file.parent() {
+        fs::create_dir_all(log_dir).context("failed to create log directory")?;
+    }
+    let log = File::create(&log_file).context("failed to open log")?;
+    body_cloned.log_file = log_file.to_string_lossy().into_owned();
+    let mut command = Command::new(&body.bin_path);
+    // stderr too, so a panicking core leaves its trace next to its log
+    command
+        .args(args)
+        .stderr(log.try_clone().context("failed to open log")?)
+        .stdout(log);
+    if body.clean_env.unwrap_or(false) {
+        clean_env(&mut command);
+    }
+    #[cfg(windows)]
+    if let Some(priority) = body.priority {
+        use std::os::windows::process::CommandExt;
+        command.creation_flags(priority_class(priority));
+    }
+    // stopping the old core takes a while, the binary may have been swapped since the check
+    if let Some(expected) = body.expected_sha256.as_deref() {
+        check_binary_hash(&body.bin_path, expected)?;
+    }
+    // only the unix priority setup below may kill the child
+    #[cfg_attr(not(unix), allow(unused_mut))]
+    let mut child = command.spawn()?;
+    #[cfg(unix)]
+    if let Some(priority) = body.priority {
+        if let Err(err) = set_priority(child.id(), priority) {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(err).context("failed to set core priority");
+        }
+    }
 
     let mut arc = ClashStatus::global().lock();
     arc.info = Some(body_cloned);
+    arc.child = Some(child);
+    arc.last_running_time = Some(Instant::now());
+    arc.generation += 1;
+
+    if let Some(interval) = body.watchdog_interval_ms {
+        let max_failures = body.watchdog_max_failures.unwrap_or(3);
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(watchdog(
+                arc.generation,
+                arc.owner.clone(),
+                Duration::from_millis(interval),
+                max_failures,
+            ));
+        }
+    }
+
+    Ok(arc.generation)
+}
+
+/// 内核日志文件的路径，相对路径放在 config_dir 下
+fn resolve_log_file(config_dir: &str, log_file: &str) -> PathBuf {
+    Path::new(config_dir).join(log_file)
+}
+
+/// 检查启动参数，在停止正在运行的内核之前调用
+fn check_start_body(body: &StartBody) -> Result<()> {
+    if !Path::new(&body.bin_path).is_file() {
+        bail!(ServiceError::new(
+            ErrorCode::BinaryNotFound,
+            format!("core binary not found: {}", body.bin_path)
+        ));
+    }
+
+    if let Some(priority) = body.priority {
+        if !(-20..=19).contains(&priority) {
+            bail!(ServiceError::new(
+                ErrorCode::BadCommand,
+                format!("priority must be between -20 and 19: {priority}")
+            ));
+        }
+    }
+
+    // mihomo fails obscurely on these, so catch them before spawning
+    if !Path::new(&body.config_dir).is_dir() {
+        bail!(ServiceError::new(
+            ErrorCode::BadCommand,
+            format!("config_dir is not a directory: {}", body.config_dir)
+        ));
+    }
+    if !Path::new(&body.config_file).is_file() {
+        bail!(ServiceError::new(
+            ErrorCode::BadCommand,
+            format!("config_file is not a file: {}", body.config_file)
+        ));
+    }
+
+    if let Some(interval) = body.watchdog_interval_ms {
+        if interval < WATCHDOG_MIN_INTERVAL_MS {
+            bail!(ServiceError::new(
+                ErrorCode::BadCommand,
+                format!(
+                    "watchdog_interval_ms must be at least {WATCHDOG_MIN_INTERVAL_MS}: {interval}"
+                )
+            ));
+        }
+    }
+
+    // hash before the known-good core is stopped, not after
+    if let Some(expected) = body.expected_sha256.as_deref() {
+        check_binary_hash(&body.bin_path, expected)?;
+    }
+
+    Ok(())
+}
+
+fn check_binary_hash(bin_path: &str, expected: &str) -> Result<()> {
+    let (_, actual) = sha256_file(bin_path)?;
+    if !actual.eq_ignore_ascii_case(expected.trim()) {
+        bail!(ServiceError::new(
+            ErrorCode::HashMismatch,
+            format!("core binary sha256 is {actual}, expected {expected}")
+        ));
+    }
+    Ok(())
+}
+
+/// 在阻塞线程上启动内核，客户端在启动完成前断开时停止刚启动的内核
+pub async fn start_clash_cancellable(body: StartBody) -> Result<()> {
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    tokio::task::spawn_blocking(move || {
+        let started = {
+            let _control = core_control().lock();
+            start_core(body)
+        };
+        // the receiver is gone when the request was dropped, nobody wants this core
+        if let Err(Ok(generation)) = tx.send(started) {
+            stop_core_if_current(generation);
+        }
+    });
+    rx.await.context("start_clash was interrupted")?.map(|_| ())
+}
+
+/// 只在内核还是 `generation` 那一次启动的时候停止，别的客户端后来启动的内核不动
+fn stop_core_if_current(generation: u64) {
+    let _control = core_control().lock();
 
+    if ClashStatus::global().lock().generation == generation {
+        stop_core();
+    }
+}
+
+#[cfg(unix)]
+fn set_priority(pid: u32, priority: i32) -> std::io::Result<()> {
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, pid as libc::id_t, priority) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
     Ok(())
 }
 
+/// 把 niceness 映射到 Windows 的优先级类
+#[cfg(windows)]
+fn priority_class(priority: i32) -> u32 {
+    const HIGH_PRIORITY_CLASS: u32 = 0x0000_0080;
+    const ABOVE_NORMAL_PRIORITY_CLASS: u32 = 0x0000_8000;
+    const NORMAL_PRIORITY_CLASS: u32 = 0x0000_0020;
+    const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x0000_4000;
+    const IDLE_PRIORITY_CLASS: u32 = 0x0000_0040;
+
+    match priority {
+        -20..=-10 => HIGH_PRIORITY_CLASS,
+        -9..=-1 => ABOVE_NORMAL_PRIORITY_CLASS,
+        0 => NORMAL_PRIORITY_CLASS,
+        1..=9 => BELOW_NORMAL_PRIORITY_CLASS,
+        _ => IDLE_PRIORITY_CLASS,
+    }
+}
+
+/// 只保留白名单里的环境变量，并使用最小的 PATH
+/// LD_PRELOAD、LD_LIBRARY_PATH、DYLD_* 之类都不会传给内核
+fn clean_env(command: &mut Command) {
+    #[cfg(not(windows))]
+    const KEEP: &[&str] = &["HOME", "USER", "LOGNAME", "LANG", "LC_ALL", "TZ", "TMPDIR"];
+    #[cfg(windows)]
+    const KEEP: &[&str] = &[
+        "SystemRoot",
+        "SystemDrive",
+        "windir",
+        "ComSpec",
+        "PATHEXT",
+        "ProgramData",
+        "ProgramFiles",
+        "USERPROFILE",
+        "APPDATA",
+        "LOCALAPPDATA",
+        "TEMP",
+        "TMP",
+    ];
+
+    #[cfg(not(windows))]
+    let path = String::from("/usr/sbin:/usr/bin:/sbin:/bin");
+    #[cfg(windows)]
+    let path = {
+        let root = std::env::var("SystemRoot").unwrap_or_else(|_| r"C:\Windows".into());
+        format!(r"{root}\System32;{root};{root}\System32\Wbem")
+    };
+
+    command.env_clear().env("PATH", path);
+    for key in KEEP {
+        if let Some(value) = std::env::var_os(key) {
+            command.env(key, value);
+        }
+    }
+}
+
+/// POST /start_clash_from_file
+/// 从文件读取启动参数并启动clash进程
+pub fn start_clash_from_file(body: StartFileBody) -> Result<()> {
+    let path = Path::new(&body.path);
+    if !path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+        bail!(ServiceError::new(
+            ErrorCode::BadCommand,
+            format!(
+                "start file must be an absolute path without '..': {}",
+                body.path
+            )
+        ));
+    }
+
+    // check and read the same open file, a path swapped in between can't slip past the checks
+    let file = open_start_file(path).with_context(|| format!("failed to read {}", body.path))?;
+    let meta = file
+        .metadata()
+        .with_context(|| format!("failed to read {}", body.path))?;
+    if !meta.is_file() {
+        bail!(ServiceError::new(
+            ErrorCode::BadCommand,
+            format!("start file is not a regular file: {}", body.path)
+        ));
+    }
+    check_start_file_mode(&meta)?;
+    if meta.len() > START_FILE_LIMIT {
+        bail!(ServiceError::new(
+            ErrorCode::BadCommand,
+            format!(
+                "start file is larger than {START_FILE_LIMIT} bytes: {}",
+                body.path
+            )
+        ));
+    }
+
+    // the file may still grow after the check
+    let mut content = String::new();
+    file.take(START_FILE_LIMIT)
+        .read_to_string(&mut content)
+        .with_context(|| format!("failed to read {}", body.path))?;
+    let start_body: StartBody =
+        serde_json::from_str(&content).context("start file is not a valid start body")?;
+
+    start_clash(start_body)
+}
+
+#[cfg(unix)]
+fn open_start_file(path: &Path) -> std::io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    // a fifo would block the open until someone writes to it
+    fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+}
+
+#[cfg(windows)]
+fn open_start_file(path: &Path) -> std::io::Result<File> {
+    File::open(path)
+}
+
+/// the service runs privileged, so don't trust a file any user could have rewritten
+#[cfg(unix)]
+fn check_start_file_mode(meta: &fs::Metadata) -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    if meta.mode() & 0o002 != 0 {
+        bail!(ServiceError::new(
+            ErrorCode::PermissionDenied,
+            "start file must not be world-writable"
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn check_start_file_mode(_meta: &fs::Metadata) -> Result<()> {
+    Ok(())
+}
+
+/// POST /switch_config
+/// 切换配置文件，其余启动参数不变，重启clash进程
+pub fn switch_config(body: SwitchConfigBody) -> Result<()> {
+    let _control = core_control().lock();
+
+    let mut start_body = match ClashStatus::global().lock().info.clone() {
+        Some(info) => info,
+        None => bail!(ServiceError::new(
+            ErrorCode::CoreNotRunning,
+            "clash not executed"
+        )),
+    };
+
+    if !Path::new(&body.config_file).is_file() {
+        bail!(ServiceError::new(
+            ErrorCode::BadCommand,
+            format!("config file not found: {}", body.config_file)
+        ));
+    }
+
+    start_body.config_file = body.config_file;
+    start_core(start_body).map(|_| ())
+}
+
+/// 保存当前内核的启动参数，重启后的服务进程用它重新启动内核
+pub fn save_core_for_restart() -> Result<()> {
+    let info = match ClashStatus::global().lock().info.clone() {
+        Some(info) => info,
+        None => return Ok(()),
+    };
+
+    let state_dir = state_dir();
+    fs::create_dir_all(&state_dir)
+        .with_context(|| format!("failed to create {}", state_dir.display()))?;
+    let resume_file = state_dir.join(RESUME_FILE);
+    fs::write(&resume_file, serde_json::to_vec(&info)?)
+        .with_context(|| format!("failed to write {}", resume_file.display()))
+}
+
+/// 重启失败时丢弃保存的启动参数
+pub fn discard_core_for_restart() {
+    let _ = fs::remove_file(state_dir().join(RESUME_FILE));
+}
+
+/// 服务启动时恢复重启前运行的内核
+pub fn resume_core() -> Result<()> {
+    let resume_file = state_dir().join(RESUME_FILE);
+    let content = match fs::read_to_string(&resume_file) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).context("failed to read the core to resume"),
+    };
+    // only ever resume once, even if the start below fails
+    let _ = fs::remove_file(&resume_file);
+
+    let body: StartBody = serde_json::from_str(&content).context("invalid core to resume")?;
+    start_clash(body)
+}
+
 /// POST /stop_clash
-/// 停止clash进程
-pub fn stop_clash() -> Result<()> {
-    let mut arc = ClashStatus::global().lock();
+/// 停止clash进程，返回每一步的清理结果
+pub fn stop_clash() -> Result<StopResult> {
+    let _control = core_control().lock();
 
-    arc.info = None;
+    Ok(stop_core())
+}
 
-    let mut system = System::new();
-    system.refresh_all();
-    let procs = system.processes_by_name("verge-mihomo");
+/// 停止内核，调用者要持有 `core_control`
+fn stop_core() -> StopResult {
+    // only hold the status lock to take the child, the kill and sweep below are slow
+    let child = {
+        let mut arc = ClashStatus::global().lock();
+        arc.info = None;
+        arc.last_running_time = None;
+        arc.generation += 1;
+        arc.child.take()
+    };
+
+    let mut result = StopResult::default();
+
+    // always try the child we spawned first, the sweep below is best-effort
+    if let Some(mut child) = child {
+        match child.kill() {
+            Ok(()) => {
+                // reap it so the sweep below doesn't see it again
+                let _ = child.wait();
+                result.child_killed = Some(true);
+            }
+            Err(err) => {
+                result.child_killed = Some(false);
+                result.child_error = Some(err.to_string());
+            }
+        }
+    }
+
+    let sweep_start = Instant::now();
+    // only the process list is needed, skip cpu, memory, disks and networks
+    let system =
+        System::new_with_specifics(RefreshKind::new().with_processes(ProcessRefreshKind::new()));
+    // sandboxed environments may hide /proc, the list always holds this service otherwise
+    result.sweep_available = !system.processes().is_empty();
+    let procs = system.processes_by_name(CORE_PROCESS_NAME);
     for proc in procs {
-        proc.kill();
+        let pid = proc.pid().as_u32();
+        if proc.kill() {
+            result.swept.push(pid);
+        } else {
+            result.failed.push(pid);
+        }
     }
+    result.sweep_ms = sweep_start.elapsed().as_millis() as u64;
+    result
+}
+
+/// GET /list_core_processes
+/// 列出所有看起来像内核的进程，包括不是本服务启动的
+pub fn list_core_processes() -> Result<Vec<CoreProcess>> {
+    let managed = ClashStatus::global()
+        .lock()
+        .child
+        .as_ref()
+        .map(|child| child.id());
+
+    let system = System::new_with_specifics(
+        RefreshKind::new().with_processes(
+            ProcessRefreshKind::new()
+                .with_cmd(UpdateKind::Always)
+                .with_exe(UpdateKind::Always),
+        ),
+    );
+    let procs = system
+        .processes_by_name(CORE_PROCESS_NAME)
+        .map(|proc| CoreProcess {
+            pid: proc.pid().as_u32(),
+            name: proc.name().into(),
+            cmd: proc.cmd().to_vec(),
+            exe: proc.exe().map(|exe| exe.to_string_lossy().into_owned()),
+            managed: Some(proc.pid().as_u32()) == managed,
+        })
+        .collect();
+    Ok(procs)
+}
+
+/// POST /kill_core_process
+/// 结束一个孤立的内核进程，拒绝结束其他进程
+pub fn kill_core_process(body: KillProcessBody) -> Result<bool> {
+    let managed = ClashStatus::global()
+        .lock()
+        .child
+        .as_ref()
+        .map(|child| child.id());
+    if managed == Some(body.pid) {
+        bail!(ServiceError::new(
+            ErrorCode::BadCommand,
+            "the managed core is stopped with /stop_clash"
+        ));
+    }
+
+    // look the pid up right before killing it, so a reused pid isn't hit
+    let pid = Pid::from_u32(body.pid);
+    let mut system = System::new();
+    system.refresh_process_specifics(pid, ProcessRefreshKind::new());
+    let proc = match system.process(pid) {
+        Some(proc) => proc,
+        None => bail!(ServiceError::new(
+            ErrorCode::BadCommand,
+            format!("no such process: {}", body.pid)
+        )),
+    };
+    if !proc.name().contains(CORE_PROCESS_NAME) {
+        bail!(ServiceError::new(
+            ErrorCode::BadCommand,
+            format!("process {} is not a core: {}", body.pid, proc.name())
+        ));
+    }
+    Ok(proc.kill())
+}
+
+/// POST /acquire_core_lock
+/// 获取内核的控制权
+pub fn acquire_core_lock(body: CoreLockBody) -> Result<()> {
+    let mut arc = ClashStatus::global().lock();
+
+    ensure_owner(&arc, Some(&body.owner_id), body.force)?;
+    arc.owner = Some(body.owner_id);
     Ok(())
 }
 
+/// POST /release_core_lock
+/// 释放内核的控制权
+pub fn release_core_lock(body: CoreLockBody) -> Result<()> {
+    let mut arc = ClashStatus::global().lock();
+
+    ensure_owner(&arc, Some(&body.owner_id), body.force)?;
+    arc.owner = None;
+    Ok(())
+}
+
+/// 检查调用者是否可以启动或停止内核
+pub fn check_core_owner(owner_id: Option<String>, force: Option<bool>) -> Result<()> {
+    let arc = ClashStatus::global().lock();
+
+    ensure_owner(&arc, owner_id.as_deref(), force)
+}
+
+fn ensure_owner(status: &ClashStatus, owner_id: Option<&str>, force: Option<bool>) -> Result<()> {
+    match status.owner.as_deref() {
+        Some(owner) if Some(owner) != owner_id && !force.unwrap_or(false) => {
+            bail!(ServiceError::new(
+                ErrorCode::CoreLocked,
+                format!("core is locked by {owner}")
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
 /// GET /get_clash
 /// 获取clash当前执行信息
-pub fn get_clash() -> Result<StartBody> {
+pub fn get_clash() -> Result<ClashInfo> {
     let arc = ClashStatus::global().lock();
 
     match arc.info.clone() {
-        Some(info) => Ok(info),
-        None => bail!("clash not executed"),
+        Some(info) => Ok(ClashInfo {
+            info,
+            running_secs: arc
+                .last_running_time
+                .map_or(0, |time| time.elapsed().as_secs()),
+            owner: arc.owner.clone(),
+        }),
+        None => bail!(ServiceError::new(
+            ErrorCode::CoreNotRunning,
+            "clash not executed"
+        )),
+    }
+}
+
+/// GET /get_controller
+/// 获取当前配置文件中的 external-controller 和 secret
+pub fn get_controller() -> Result<ControllerInfo> {
+    let config_file = match ClashStatus::global().lock().info.as_ref() {
+        Some(info) => info.config_file.clone(),
+        None => bail!(ServiceError::new(
+            ErrorCode::CoreNotRunning,
+            "clash not executed"
+        )),
+    };
+
+    let content = fs::read_to_string(&config_file)
+        .with_context(|| format!("failed to read config file {config_file}"))?;
+    // the error may quote the secret, so don't pass it through
+    match serde_yaml::from_str(&content) {
+        Ok(info) => Ok(info),
+        Err(_) => bail!("failed to parse config file {config_file}"),
+    }
+}
+
+/// GET /read_config_file
+/// 分块读取内核正在使用的配置文件，GUI 可能没有权限直接读取
+pub fn read_config_file(query: ChunkQuery) -> Result<ConfigChunk> {
+    let config_file = match ClashStatus::global().lock().info.as_ref() {
+        Some(info) => info.config_file.clone(),
+        None => bail!(ServiceError::new(
+            ErrorCode::CoreNotRunning,
+            "clash not executed"
+        )),
+    };
+
+    let offset = query.offset.unwrap_or(0);
+    let limit = query
+        .limit
+        .unwrap_or(CONFIG_CHUNK_LIMIT)
+        .min(CONFIG_CHUNK_LIMIT);
+
+    let read = || -> std::io::Result<(u64, Vec<u8>)> {
+        let mut file = File::open(&config_file)?;
+        let size = file.metadata()?.len();
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::new();
+        file.take(limit as u64).read_to_end(&mut data)?;
+        Ok((size, data))
+    };
+    let (size, data) = match read() {
+        Ok(chunk) => chunk,
+        Err(err) => bail!(ServiceError::new(
+            ErrorCode::PermissionDenied,
+            format!("failed to read config file {config_file}: {err}")
+        )),
+    };
+
+    Ok(ConfigChunk {
+        eof: offset + data.len() as u64 >= size,
+        path: config_file,
+        size,
+        offset,
+        data: BASE64_STANDARD.encode(data),
+    })
+}
+
+/// POST /wait_core_ready
+/// 等待内核的 external controller 可以连接
+pub async fn wait_core_ready(body: WaitBody) -> Result<()> {
+    // reads and parses the whole config, keep it off the async workers
+    let controller = super::blocking(running_controller).await?;
+
+    let timeout = body.timeout_ms.min(WAIT_TIMEOUT_LIMIT_MS);
+    let deadline = Instant::now() + Duration::from_millis(timeout);
+    loop {
+        // a connect that hangs must not carry the wait past its deadline
+        let attempt = deadline
+            .saturating_duration_since(Instant::now())
+            .min(PROBE_TIMEOUT);
+        if tokio::time::timeout(attempt, probe_controller(&controller))
+            .await
+            .unwrap_or(false)
+        {
+            return Ok(());
+        }
+        if core_exited() {
+            bail!(ServiceError::new(
+                ErrorCode::CoreNotRunning,
+                "core exited before it was ready"
+            ));
+        }
+        if Instant::now() >= deadline {
+            bail!(ServiceError::new(
+                ErrorCode::Timeout,
+                "core was not ready in time"
+            ));
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// POST /wait_core_exit
+/// 等待内核进程退出，返回退出码
+pub async fn wait_core_exit(body: WaitBody) -> Result<CoreExit> {
+    let generation = {
+        let arc = ClashStatus::global().lock();
+        if arc.child.is_none() {
+            bail!(ServiceError::new(
+                ErrorCode::CoreNotRunning,
+                "clash not executed"
+            ));
+        }
+        arc.generation
+    };
+
+    let timeout = body.timeout_ms.min(WAIT_TIMEOUT_LIMIT_MS);
+    let deadline = Instant::now() + Duration::from_millis(timeout);
+    loop {
+        {
+            let mut arc = ClashStatus::global().lock();
+            // stop_clash reaps the child itself, its status is gone
+            if arc.generation != generation {
+                return Ok(CoreExit { exit_code: None });
+            }
+            if let Some(child) = arc.child.as_mut() {
+                if let Ok(Some(status)) = child.try_wait() {
+                    return Ok(CoreExit {
+                        exit_code: status.code(),
+                    });
+                }
+            }
+        }
+        if Instant::now() >= deadline {
+            bail!(ServiceError::new(
+                ErrorCode::Timeout,
+                "core did not exit in time"
+            ));
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// 读取当前内核的 external controller，至少要有一个
+fn running_controller() -> Result<ControllerInfo> {
+    let mut controller = get_controller()?;
+    let config_dir = match ClashStatus::global().lock().info.as_ref() {
+        Some(info) => info.config_dir.clone(),
+        None => bail!(ServiceError::new(
+            ErrorCode::CoreNotRunning,
+            "clash not executed"
+        )),
+    };
+    if controller.external_controller.is_none()
+        && controller.external_controller_unix.is_none()
+        && controller.external_controller_pipe.is_none()
+    {
+        bail!(ServiceError::new(
+            ErrorCode::BadCommand,
+            "core config has no external controller"
+        ));
+    }
+
+    // mihomo resolves a relative socket path against its home dir
+    controller.external_controller_unix = controller.external_controller_unix.map(|path| {
+        Path::new(&config_dir)
+            .join(path)
+            .to_string_lossy()
+            .into_owned()
+    });
+
+    Ok(controller)
+}
+
+/// 定期探测 external controller，内核活着但连续无响应时重启内核
+async fn watchdog(generation: u64, owner: Option<String>, interval: Duration, max_failures: u32) {
+    let is_current = || ClashStatus::global().lock().generation == generation;
+
+    // the config doesn't change for the life of this generation, read it once
+    let controller = match tokio::task::spawn_blocking(running_controller).await {
+        Ok(Ok(controller)) => controller,
+        _ => return,
+    };
+
+    // a core that is still starting up is not hung, only count after it answered once
+    let mut answered = false;
+    let mut failures = 0;
+    loop {
+        tokio::time::sleep(interval).await;
+        // a dead core is not ours to restart, and a restart or stop retires this watchdog
+        if !is_current() || core_exited() {
+            return;
+        }
+
+        if ping_controller(&controller, interval).await {
+            answered = true;
+            failures = 0;
+            continue;
+        }
+        if !answered {
+            continue;
+        }
+        failures += 1;
+        if failures < max_failures {
+            continue;
+        }
+
+        let _ =
+            tokio::task::spawn_blocking(move || restart_core_if_current(generation, owner)).await;
+        return;
+    }
+}
+
+/// 看门狗重启内核，期间有客户端停止、重启内核或拿走了内核锁就不动
+fn restart_core_if_current(generation: u64, owner: Option<String>) -> Result<()> {
+    let _control = core_control().lock();
+
+    let info = {
+        let arc = ClashStatus::global().lock();
+        match arc.info.clone() {
+            Some(info) if arc.generation == generation && arc.owner == owner => info,
+            _ => return Ok(()),
+        }
+    };
+    start_core(info).map(|_| ())
+}
+
+/// 内核进程是否已经退出
+fn core_exited() -> bool {
+    let mut arc = ClashStatus::global().lock();
+
+    match arc.child.as_mut() {
+        Some(child) => !matches!(child.try_wait(), Ok(None)),
+        None => true,
+    }
+}
+
+/// 尝试连接任意一个 external controller
+async fn probe_controller(controller: &ControllerInfo) -> bool {
+    if let Some(addr) = controller
+        .external_controller
+        .as_deref()
+        .and_then(tcp_target)
+    {
+        if tokio::net::TcpStream::connect(addr).await.is_ok() {
+            return true;
+        }
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = controller.external_controller_unix.as_deref() {
+        if tokio::net::UnixStream::connect(path).await.is_ok() {
+            return true;
+        }
+    }
+
+    #[cfg(windows)]
+    if let Some(name) = controller.external_controller_pipe.as_deref() {
+        const ERROR_PIPE_BUSY: i32 = 231;
+
+        // a busy pipe is up, it's just serving someone else
+        match tokio::net::windows::named_pipe::ClientOptions::new().open(name) {
+            Ok(_) => return true,
+            Err(err) if err.raw_os_error() == Some(ERROR_PIPE_BUSY) => return true,
+            Err(_) => {}
+        }
+    }
+
+    false
+}
+
+/// 向 external controller 发一个请求，在超时前收到任何响应就算活着
+/// 进程挂起时内核仍会替它完成 TCP 握手，所以只连接是不够的
+async fn ping_controller(controller: &ControllerInfo, timeout: Duration) -> bool {
+    let ping = async {
+        if let Some(addr) = controller
+            .external_controller
+            .as_deref()
+            .and_then(tcp_target)
+        {
+            if let Ok(stream) = tokio::net::TcpStream::connect(addr).await {
+                return http_ping(stream).await;
+            }
+        }
+
+        #[cfg(unix)]
+        if let Some(path) = controller.external_controller_unix.as_deref() {
+            if let Ok(stream) = tokio::net::UnixStream::connect(path).await {
+                return http_ping(stream).await;
+            }
+        }
+
+        #[cfg(windows)]
+        if let Some(name) = controller.external_controller_pipe.as_deref() {
+            if let Ok(pipe) = tokio::net::windows::named_pipe::ClientOptions::new().open(name) {
+                return http_ping(pipe).await;
+            }
+        }
+
+        false
+    };
+    tokio::time::timeout(timeout, ping).await.unwrap_or(false)
+}
+
+async fn http_ping<S>(mut stream: S) -> bool
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // even a 401 for a missing secret means the core is serving
+    let request = b"GET /version HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\n\r\n";
+    if stream.write_all(request).await.is_err() {
+        return false;
+    }
+    let mut buf = [0u8; 1];
+    matches!(stream.read(&mut buf).await, Ok(n) if n > 0)
+}
+
+/// external-controller 的地址，监听所有网卡时改用回环地址
+fn tcp_target(addr: &str) -> Option<(&str, u16)> {
+    let (host, port) = addr.rsplit_once(':')?;
+    let host = match host.trim_matches(|c| c == '[' || c == ']') {
+        "" | "0.0.0.0" => "127.0.0.1",
+        "::" => "::1",
+        host => host,
+    };
+    Some((host, port.parse().ok()?))
+}
+
+/// GET /get_log_path
+/// 获取内核日志文件的绝对路径
+pub fn get_log_path() -> Result<LogPaths> {
+    let arc = ClashStatus::global().lock();
+
+    let core_log = arc.info.as_ref().map(|info| {
+        fs::canonicalize(&info.log_file)
+            .map(|path| path.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| info.log_file.clone())
+    });
+
+    Ok(LogPaths { core_log })
+}
+
+/// GET /diagnostics
+/// 收集服务和内核的诊断信息，单项失败不影响其他项
+pub fn get_diagnostics() -> Result<Diagnostics> {
+    let mut report = Diagnostics {
+        version: env!("CARGO_PKG_VERSION").into(),
+        uptime_secs: service_started_at().elapsed().as_secs(),
+        os: std::env::consts::OS.into(),
+        arch: std::env::consts::ARCH.into(),
+        listen_addr: format!("127.0.0.1:{}", super::LISTEN_PORT),
+        ..Default::default()
+    };
+
+    let mut arc = ClashStatus::global().lock();
+
+    if let Some(child) = arc.child.as_mut() {
+        report.core_pid = Some(child.id());
+        match child.try_wait() {
+            Ok(None) => report.core_running = true,
+            Ok(Some(status)) => report.core_exit_code = status.code(),
+            Err(err) => report
+                .errors
+                .push(format!("failed to query core status: {err}")),
+        }
+    }
+
+    if let Some(info) = arc.info.as_ref() {
+        report.core_log = Some(info.log_file.clone());
+        match fs::metadata(&info.log_file) {
+            Ok(meta) => report.core_log_size = Some(meta.len()),
+            Err(err) => report
+                .errors
+                .push(format!("failed to stat core log: {err}")),
+        }
+
+        match fs::metadata(&info.bin_path) {
+            Ok(meta) => {
+                report.bin_exists = Some(true);
+                report.bin_executable = Some(is_executable(&info.bin_path, &meta));
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                report.bin_exists = Some(false);
+                report.bin_executable = Some(false);
+            }
+            Err(err) => report
+                .errors
+                .push(format!("failed to stat core binary: {err}")),
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(unix)]
+fn is_executable(_path: &str, meta: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    meta.is_file() && meta.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(windows)]
+fn is_executable(path: &str, meta: &fs::Metadata) -> bool {
+    meta.is_file()
+        && Path::new(path)
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("exe"))
+}
+
+/// POST /get_core_binary_hash
+/// 计算内核二进制文件的 SHA-256
+pub fn get_core_binary_hash(body: BinaryHashBody) -> Result<BinaryHash> {
+    let (size, sha256) = sha256_file(&body.bin_path)?;
+
+    Ok(BinaryHash {
+        bin_path: body.bin_path,
+        size,
+        sha256,
+    })
+}
+
+/// 流式计算文件的 SHA-256，返回文件大小和十六进制摘要
+fn sha256_file(path: &str) -> Result<(u64, String)> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => bail!(ServiceError::new(
+            ErrorCode::BinaryNotFound,
+            format!("core binary not found: {path}")
+        )),
+        Err(err) => return Err(err).with_context(|| format!("failed to open {path}")),
+    };
+
+    let size = file.metadata()?.len();
+    if size > CORE_BINARY_HASH_LIMIT {
+        bail!(ServiceError::new(
+            ErrorCode::BadCommand,
+            format!("core binary is too large to hash: {size} bytes")
+        ));
+    }
+
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file.take(CORE_BINARY_HASH_LIMIT), &mut hasher)
+        .with_context(|| format!("failed to read {path}"))?;
+    let sha256 = hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+
+    Ok((size, sha256))
+}
+
+/// GET /get_service_resource_usage
+/// 获取服务进程自身的内存、CPU 和线程数
+pub fn get_service_resource_usage() -> Result<ResourceUsage> {
+    let pid = Pid::from_u32(std::process::id());
+    let refresh = ProcessRefreshKind::new().with_cpu().with_memory();
+
+    // cpu usage is measured between two refreshes
+    let mut system = System::new();
+    system.refresh_process_specifics(pid, refresh);
+    std::thread::sleep(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL);
+    system.refresh_process_specifics(pid, refresh);
+
+    let proc = system
+        .process(pid)
+        .context("failed to read the service process")?;
+    Ok(ResourceUsage {
+        pid: pid.as_u32(),
+        rss_bytes: proc.memory(),
+        cpu_percent: proc.cpu_usage(),
+        threads: thread_count(),
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn thread_count() -> Option<usize> {
+    fs::read_dir("/proc/self/task")
+        .ok()
+        .map(|tasks| tasks.count())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn thread_count() -> Option<usize> {
+    None
+}
+
+/// GET /check_privileges
+/// 检查服务是否有创建 TUN 设备需要的权限
+pub fn check_privileges() -> Result<Privileges> {
+    let elevated = privileges::is_elevated();
+    let net_admin = privileges::has_net_admin();
+
+    Ok(Privileges {
+        elevated,
+        net_admin,
+        tun_capable: net_admin.unwrap_or(elevated),
+    })
+}
+
+/// GET /get_service_journal
+/// 读取系统日志里服务自己的记录（journald、macOS 统一日志、Windows 事件日志），最新的在最后
+pub fn get_service_journal(query: LimitQuery) -> Result<Vec<String>> {
+    let limit = query
+        .limit
+        .unwrap_or(SERVICE_JOURNAL_LIMIT)
+        .min(SERVICE_JOURNAL_LIMIT);
+
+    let mut lines = service_journal(limit).context("failed to read the service journal")?;
+    Ok(lines.split_off(lines.len().saturating_sub(limit)))
+}
+
+#[cfg(target_os = "linux")]
+fn service_journal(limit: usize) -> std::io::Result<Vec<String>> {
+    let output = Command::new("journalctl")
+        .args(["-u", "clash-verge-service", "-o", "short-iso"])
+        .args(["--no-pager", "--quiet"])
+        .arg(format!("--lines={limit}"))
+        .run_checked()?;
+    Ok(output_lines(&output.stdout))
+}
+
+#[cfg(target_os = "macos")]
+fn service_journal(limit: usize) -> std::io::Result<Vec<String>> {
+    // log show is slow over long ranges, the last day is enough to see why it failed.
+    // launchd, not the helper, logs why the helper failed to launch or exited
+    let predicate = concat!(
+        r#"(process == "io.github.clashverge.helper") || "#,
+        r#"(subsystem == "com.apple.xpc.launchd" && eventMessage CONTAINS "io.github.clashverge.helper")"#,
+    );
+    let output = Command::new("log")
+        .args(["show", "--last", "1d", "--style", "compact"])
+        .args(["--predicate", predicate])
+        .run_checked()?;
+
+    // log show has no line limit of its own
+    let mut lines = output_lines(&output.stdout);
+    Ok(lines.split_off(lines.len().saturating_sub(limit)))
+}
+
+#[cfg(windows)]
+fn service_journal(limit: usize) -> std::io::Result<Vec<String>> {
+    // the service control manager logs state changes by display name
+    let output = Command::new("wevtutil")
+        .args(["qe", "System", "/rd:true", "/f:text"])
+        .arg(format!("/c:{limit}"))
+        .arg(
+            "/q:*[System[Provider[@Name='Service Control Manager']] \
+             and EventData[Data='Clash Verge Service']]",
+        )
+        .run_checked()?;
+
+    // newest event comes first, flip the events but keep each one's lines in order
+    let mut events: Vec<Vec<String>> = Vec::new();
+    for line in output_lines(&output.stdout) {
+        match events.last_mut() {
+            Some(event) if !line.starts_with("Event[") => event.push(line),
+            _ => events.push(vec![line]),
+        }
+    }
+    Ok(events.into_iter().rev().flatten().collect())
+}
+
+#[cfg(not(any(windows, target_os = "linux", target_os = "macos")))]
+fn service_journal(_limit: usize) -> std::io::Result<Vec<String>> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "no system journal on this platform",
+    ))
+}
+
+fn output_lines(stdout: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(stdout)
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// GET /get_connection_count
+/// 获取当前打开的客户端连接数，包括这次请求自己的连接
+pub fn get_connection_count() -> Result<usize> {
+    Ok(connections::active_connections())
+}
+
+/// GET /get_disk_usage
+/// 获取配置目录和日志目录所在磁盘的剩余空间，没有运行的内核时可以在启动前传入要检查的路径
+pub fn get_disk_usage(query: DiskUsageQuery) -> Result<Vec<DiskSpace>> {
+    let (config_dir, log_file) = match query.config_dir {
+        Some(config_dir) => (config_dir, query.log_file),
+        None => match ClashStatus::global().lock().info.as_ref() {
+            Some(info) => (
+                info.config_dir.clone(),
+                Some(query.log_file.unwrap_or_else(|| info.log_file.clone())),
+            ),
+            None => bail!(ServiceError::new(
+                ErrorCode::CoreNotRunning,
+                "clash not executed, pass config_dir to check before starting it"
+            )),
+        },
+    };
+    // the log file itself may not exist yet, its directory is what gets written to
+    let log_dir = log_file
+        .and_then(|log_file| {
+            resolve_log_file(&config_dir, &log_file)
+                .parent()
+                .map(|dir| dir.to_string_lossy().into_owned())
+        })
+        .unwrap_or_else(|| config_dir.clone());
+
+    let disks = Disks::new_with_refreshed_list();
+    let usage = |path: String| {
+        let mut space = DiskSpace {
+            path,
+            ..Default::default()
+        };
+        // the core creates a missing log directory, the disk that will hold it is what counts
+        let resolved = Path::new(&space.path)
+            .ancestors()
+            .find_map(|dir| fs::canonicalize(dir).ok());
+        let resolved = match resolved {
+            Some(resolved) => resolved,
+            None => {
+                space.error = Some("path not found".into());
+                return space;
+            }
+        };
+        // the deepest mount point containing the path is its filesystem
+        let disk = disks
+            .list()
+            .iter()
+            .filter(|disk| resolved.starts_with(disk.mount_point()))
+            .max_by_key(|disk| disk.mount_point().components().count());
+        match disk {
+            Some(disk) => {
+                space.mount_point = Some(disk.mount_point().to_string_lossy().into_owned());
+                space.total_bytes = disk.total_space();
+                space.available_bytes = disk.available_space();
+                space.used_bytes = disk.total_space().saturating_sub(disk.available_space());
+            }
+            None => space.error = Some("no filesystem found for path".into()),
+        }
+        space
+    };
+
+    Ok(vec![usage(config_dir), usage(log_dir)])
+}
+
+/// GET /get_install_info
+/// 获取安装服务时记录的安装信息
+pub fn get_install_info() -> Result<InstallInfo> {
+    let info_file = state_dir().join(INSTALL_INFO_FILE);
+    let content = fs::read_to_string(&info_file)
+        .with_context(|| format!("no install info at {}", info_file.display()))?;
+
+    serde_json::from_str(&content).context("invalid install info")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a fresh directory under the system temp dir, unique per test
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "clash-verge-service-test-{}-{name}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// a start body whose config_dir and config_file exist
+    fn valid_body(dir: &Path) -> StartBody {
+        let config_file = dir.join("config.yaml");
+        fs::write(&config_file, "mixed-port: 7890\n").unwrap();
+        fs::write(dir.join("verge-mihomo"), "core").unwrap();
+
+        StartBody {
+            core_type: None,
+            bin_path: dir.join("verge-mihomo").to_string_lossy().into_owned(),
+            config_dir: dir.to_string_lossy().into_owned(),
+            config_file: config_file.to_string_lossy().into_owned(),
+            log_file: "service.log".into(),
+            clean_env: None,
+            priority: None,
+            expected_sha256: None,
+            watchdog_interval_ms: None,
+            watchdog_max_failures: None,
+        }
+    }
+
+    fn error_code(result: Result<()>) -> ErrorCode {
+        ErrorCode::of(&result.expect_err("expected the start body to be rejected"))
+    }
+
+    #[test]
+    fn start_body_accepts_existing_config() {
+        let dir = temp_dir("valid");
+        check_start_body(&valid_body(&dir)).unwrap();
+    }
+
+    #[test]
+    fn start_body_rejects_missing_binary() {
+        let dir = temp_dir("missing-binary");
+        let mut body = valid_body(&dir);
+        body.bin_path = dir.join("missing").to_string_lossy().into_owned();
+
+        assert_eq!(
+            error_code(check_start_body(&body)),
+            ErrorCode::BinaryNotFound
+        );
+    }
+
+    #[test]
+    fn start_body_rejects_missing_config_dir() {
+        let dir = temp_dir("missing-dir");
+        let mut body = valid_body(&dir);
+        body.config_dir = dir.join("missing").to_string_lossy().into_owned();
+
+        assert_eq!(error_code(check_start_body(&body)), ErrorCode::BadCommand);
+    }
+
+    #[test]
+    fn start_body_rejects_config_dir_that_is_a_file() {
+        let dir = temp_dir("dir-is-file");
+        let mut body = valid_body(&dir);
+        body.config_dir = body.config_file.clone();
+
+        assert_eq!(error_code(check_start_body(&body)), ErrorCode::BadCommand);
+    }
+
+    #[test]
+    fn start_body_rejects_missing_config_file() {
+        let dir = temp_dir("missing-file");
+        let mut body = valid_body(&dir);
+        body.config_file = dir.join("missing.yaml").to_string_lossy().into_owned();
+
+        assert_eq!(error_code(check_start_body(&body)), ErrorCode::BadCommand);
+    }
+
+    #[test]
+    fn start_body_rejects_config_file_that_is_a_dir() {
+        let dir = temp_dir("file-is-dir");
+        let mut body = valid_body(&dir);
+        body.config_file = body.config_dir.clone();
+
+        assert_eq!(error_code(check_start_body(&body)), ErrorCode::BadCommand);
+    }
+
+    #[test]
+    fn start_body_checks_priority_range() {
+        let dir = temp_dir("priority");
+        let mut body = valid_body(&dir);
+
+        for priority in [-20, 0, 19] {
+            body.priority = Some(priority);
+            check_start_body(&body).unwrap();
+        }
+        for priority in [-21, 20] {
+            body.priority = Some(priority);
+            assert_eq!(error_code(check_start_body(&body)), ErrorCode::BadCommand);
+        }
+    }
+
+    #[test]
+    fn start_body_rejects_short_watchdog_interval() {
+        let dir = temp_dir("watchdog");
+        let mut body = valid_body(&dir);
+
+        body.watchdog_interval_ms = Some(WATCHDOG_MIN_INTERVAL_MS);
+        check_start_body(&body).unwrap();
+        for interval in [0, WATCHDOG_MIN_INTERVAL_MS - 1] {
+            body.watchdog_interval_ms = Some(interval);
+            assert_eq!(error_code(check_start_body(&body)), ErrorCode::BadCommand);
+        }
+    }
+
+    fn start_file(dir: &Path, content: &[u8]) -> StartFileBody {
+        let path = dir.join("start.json");
+        fs::write(&path, content).unwrap();
+        StartFileBody {
+            path: path.to_string_lossy().into_owned(),
+        }
+    }
+
+    #[test]
+    fn start_file_rejects_relative_path() {
+        let body = StartFileBody {
+            path: "start.json".into(),
+        };
+
+        assert_eq!(
+            error_code(start_clash_from_file(body)),
+            ErrorCode::BadCommand
+        );
+    }
+
+    #[test]
+    fn start_file_rejects_oversized_file() {
+        let dir = temp_dir("start-file-size");
+        let body = start_file(&dir, &vec![b' '; START_FILE_LIMIT as usize + 1]);
+
+        assert_eq!(
+            error_code(start_clash_from_file(body)),
+            ErrorCode::BadCommand
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn start_file_rejects_world_writable_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("start-file-mode");
+        let body = start_file(&dir, b"{}");
+        fs::set_permissions(&body.path, fs::Permissions::from_mode(0o666)).unwrap();
+
+        assert_eq!(
+            error_code(start_clash_from_file(body)),
+            ErrorCode::PermissionDenied
+        );
+    }
+
+    #[test]
+    fn read_only_queries_only_record_faults() {
+        assert!(!is_service_fault("get_clash", ErrorCode::CoreNotRunning));
+        assert!(!is_service_fault(
+            "get_disk_usage",
+            ErrorCode::CoreNotRunning
+        ));
+        assert!(!is_service_fault("read_config_file", ErrorCode::BadCommand));
+        assert!(!is_service_fault("wait_core_ready", ErrorCode::Timeout));
+        assert!(is_service_fault("get_controller", ErrorCode::Internal));
+        assert!(is_service_fault(
+            "read_config_file",
+            ErrorCode::PermissionDenied
+        ));
+        assert!(is_service_fault(
+            "get_core_binary_hash",
+            ErrorCode::BinaryNotFound
+        ));
+    }
+
+    #[test]
+    fn control_commands_record_every_failure() {
+        for command in CONTROL_COMMANDS {
+            assert!(is_service_fault(command, ErrorCode::BadCommand));
+            assert!(is_service_fault(command, ErrorCode::CoreNotRunning));
+            assert!(is_service_fault(command, ErrorCode::CoreLocked));
+        }
+    }
+
+    #[test]
+    fn disk_usage_checks_given_paths_without_a_core() {
+        let dir = temp_dir("disk-usage");
+        let config_dir = dir.to_string_lossy().into_owned();
+        let query = DiskUsageQuery {
+            config_dir: Some(config_dir.clone()),
+            log_file: Some("logs/core.log".into()),
+        };
+
+        let usage = get_disk_usage(query).unwrap();
+        assert_eq!(usage[0].path, config_dir);
+        assert_eq!(
+            Path::new(&usage[1].path),
+            dir.join("logs").as_path(),
+            "the log directory is resolved against config_dir"
+        );
+        assert!(usage[0].error.is_none());
+        // not created until the core starts, still on the same disk
+        assert!(usage[1].error.is_none());
+        assert_eq!(usage[1].mount_point, usage[0].mount_point);
+    }
+
+    #[test]
+    fn disk_usage_log_dir_defaults_to_config_dir() {
+        let dir = temp_dir("disk-usage-default");
+        let config_dir = dir.to_string_lossy().into_owned();
+        let query = DiskUsageQuery {
+            config_dir: Some(config_dir.clone()),
+            log_file: None,
+        };
+
+        let usage = get_disk_usage(query).unwrap();
+        assert_eq!(usage[1].path, config_dir);
+    }
+
+    #[test]
+    fn relative_log_file_is_under_config_dir() {
+        let dir = std::env::temp_dir().join("config");
+        let config_dir = dir.to_string_lossy();
+
+        assert_eq!(
+            resolve_log_file(&config_dir, "logs/core.log"),
+            dir.join("logs").join("core.log")
+        );
+    }
+
+    #[test]
+    fn absolute_log_file_is_kept() {
+        let log_file = std::env::temp_dir().join("core.log");
+        let config_dir = std::env::temp_dir().join("config");
+
+        assert_eq!(
+            resolve_log_file(&config_dir.to_string_lossy(), &log_file.to_string_lossy()),
+            log_file
+        );
+    }
+
+    #[test]
+    fn tcp_target_maps_wildcards_to_loopback() {
+        assert_eq!(tcp_target("127.0.0.1:9090"), Some(("127.0.0.1", 9090)));
+        assert_eq!(tcp_target("0.0.0.0:9090"), Some(("127.0.0.1", 9090)));
+        assert_eq!(tcp_target(":9090"), Some(("127.0.0.1", 9090)));
+        assert_eq!(tcp_target("[::]:9090"), Some(("::1", 9090)));
+        assert_eq!(tcp_target("[::1]:9090"), Some(("::1", 9090)));
+        assert_eq!(tcp_target("localhost:9090"), Some(("localhost", 9090)));
+    }
+
+    #[test]
+    fn tcp_target_rejects_bad_addresses() {
+        assert_eq!(tcp_target("127.0.0.1"), None);
+        assert_eq!(tcp_target("127.0.0.1:port"), None);
+        assert_eq!(tcp_target("127.0.0.1:70000"), None);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn priority_maps_to_priority_class() {
+        assert_eq!(priority_class(-20), 0x0000_0080);
+        assert_eq!(priority_class(-10), 0x0000_0080);
+        assert_eq!(priority_class(-1), 0x0000_8000);
+        assert_eq!(priority_class(0), 0x0000_0020);
+        assert_eq!(priority_class(9), 0x0000_4000);
+        assert_eq!(priority_class(10), 0x0000_0040);
+        assert_eq!(priority_class(19), 0x0000_0040);
+    }
+
+    /// sha256 of "core"
+    const CORE_SHA256: &str = "0d45f5fd462b8c70bffb10021ac1bcff3f58f29b1faf7568595095427d42812c";
+
+    #[test]
+    fn start_body_accepts_matching_hash() {
+        let dir = temp_dir("hash-match");
+        let mut body = valid_body(&dir);
+        // upper case and surrounding whitespace are tolerated
+        body.expected_sha256 = Some(format!(" {} ", CORE_SHA256.to_uppercase()));
+
+        check_start_body(&body).unwrap();
+    }
+
+    #[test]
+    fn start_body_rejects_mismatching_hash() {
+        let dir = temp_dir("hash-mismatch");
+        let mut body = valid_body(&dir);
+        fs::write(&body.bin_path, "tampered core").unwrap();
+        body.expected_sha256 = Some(CORE_SHA256.into());
+
+        assert_eq!(error_code(check_start_body(&body)), ErrorCode::HashMismatch);
     }
 }
@@ -2,8 +2,30 @@
 fn main() {
     panic!("This program is not intended to run on this platform.");
 }
+// only the paths are needed here, not the install info itself
+#[cfg(any(windows, target_os = "linux", target_os = "macos"))]
+#[allow(dead_code)]
+mod install_info;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod utils;
+
 #[cfg(not(windows))]
 use anyhow::Error;
+#[cfg(any(windows, target_os = "linux", target_os = "macos"))]
+use install_info::{state_dir, INSTALL_INFO_FILE, RESUME_FILE};
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use utils::OutputTimeout;
+
+/// Remove what install-service and the service left in the state directory.
+#[cfg(any(windows, target_os = "linux", target_os = "macos"))]
+fn remove_state() {
+    let state_dir = state_dir();
+    let _ = std::fs::remove_file(state_dir.join(INSTALL_INFO_FILE));
+    // left behind when the service crashed between saving the core and restarting
+    let _ = std::fs::remove_file(state_dir.join(RESUME_FILE));
+    // only once it's empty, never take anything else along
+    let _ = std::fs::remove_dir(state_dir);
+}
 
 #[cfg(target_os = "macos")]
 fn main() -> Result<(), Error> {
@@ -11,12 +33,14 @@ fn main() -> Result<(), Error> {
 
     let plist_file = "/Library/LaunchDaemons/io.github.clashverge.helper.plist";
 
-    // Unload the service.
-    std::process::Command::new("launchctl")
+    // Unload the service, keep cleaning up even if it wasn't loaded.
+    if let Err(err) = std::process::Command::new("launchctl")
         .arg("unload")
         .arg(plist_file)
-        .output()
-        .expect("Failed to unload service.");
+        .run_checked()
+    {
+        eprintln!("Failed to unload service: {err}");
+    }
 
     // Remove the service file.
     let service_file = Path::new("/Library/PrivilegedHelperTools/io.github.clashverge.helper");
@@ -29,6 +53,8 @@ fn main() -> Result<(), Error> {
     if plist_file.exists() {
         remove_file(plist_file).expect("Failed to remove plist file.");
     }
+
+    remove_state();
     Ok(())
 }
 #[cfg(target_os = "linux")]
@@ -37,13 +63,15 @@ fn main() -> Result<(), Error> {
 
     const SERVICE_NAME: &str = "clash-verge-service";
 
-    // Disable the service
-    std::process::Command::new("systemctl")
+    // Disable the service, keep cleaning up even if it fails.
+    if let Err(err) = std::process::Command::new("systemctl")
         .arg("disable")
         .arg(SERVICE_NAME)
         .arg("--now")
-        .output()
-        .expect("Failed to disable service.");
+        .run_checked()
+    {
+        eprintln!("Failed to disable service: {err}");
+    }
 
     // Remove the unit file.
     let unit_file = format!("/etc/systemd/system/{}.service", SERVICE_NAME);
@@ -54,8 +82,10 @@ fn main() -> Result<(), Error> {
 
     std::process::Command::new("systemctl")
         .arg("daemon-reload")
-        .output()
+        .run_checked()
         .expect("Failed to reload systemd daemon.");
+
+    remove_state();
     Ok(())
 }
 
@@ -84,5 +114,7 @@ fn main() -> windows_service::Result<()> {
     }
 
     service.delete()?;
+
+    remove_state();
     Ok(())
 }
@@ -0,0 +1,83 @@
+use std::{
+    io::{self, Read},
+    process::{Command, Output, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How long a system command (systemctl, launchctl, ...) may run before it is killed.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub trait OutputTimeout {
+    /// Like `Command::output`, but kills the command if it runs longer than `COMMAND_TIMEOUT`.
+    fn output_timeout(&mut self) -> io::Result<Output>;
+
+    /// Like `output_timeout`, but also fails if the command exits unsuccessfully,
+    /// with its stderr in the error message.
+    fn run_checked(&mut self) -> io::Result<Output>;
+}
+
+impl OutputTimeout for Command {
+    fn output_timeout(&mut self) -> io::Result<Output> {
+        let mut child = self
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // Drain the pipes on their own threads so a chatty command can't block on a full pipe.
+        let stdout = child.stdout.take().map(read_to_end);
+        let stderr = child.stderr.take().map(read_to_end);
+
+        let deadline = Instant::now() + COMMAND_TIMEOUT;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "{:?} timed out after {}s",
+                        self.get_program(),
+                        COMMAND_TIMEOUT.as_secs()
+                    ),
+                ));
+            }
+            thread::sleep(Duration::from_millis(50));
+        };
+
+        let join = |handle: Option<thread::JoinHandle<Vec<u8>>>| {
+            handle.and_then(|h| h.join().ok()).unwrap_or_default()
+        };
+        Ok(Output {
+            status,
+            stdout: join(stdout),
+            stderr: join(stderr),
+        })
+    }
+
+    fn run_checked(&mut self) -> io::Result<Output> {
+        let output = self.output_timeout()?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(io::Error::other(format!(
+                "{:?} failed ({}): {}",
+                self.get_program(),
+                output.status,
+                stderr.trim()
+            )));
+        }
+        Ok(output)
+    }
+}
+
+fn read_to_end(mut pipe: impl Read + Send + 'static) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = pipe.read_to_end(&mut buf);
+        buf
+    })
+}